@@ -0,0 +1,293 @@
+//! Measures the loudness of songs without ReplayGain tags on a background thread
+use crate::loudness::{Loudness, LoudnessMeter, integrated_loudness};
+use crate::replay_gain::AnalysedLoudness;
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::tag::{ItemKey, Tag, TagExt};
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+#[derive(Debug, Clone)]
+pub enum AnalysisMessage {
+    Start,           // Analyses every album with songs that have no loudness information
+    Cancel,          // Stops analysing, keeping the albums that are done
+    Poll,            // Picks up finished songs and progress
+    ToggleWriteTags, // Whether results are also written to the files as ReplayGain tags
+}
+
+/// The outcome for one song
+pub struct AnalysisResult {
+    pub path: PathBuf,
+    pub loudness: AnalysedLoudness,
+    /// The result was also written to the file's tags
+    pub written: bool,
+}
+
+/// Songs measured together, an album's songs or a song without an album
+pub struct AnalysisGroup {
+    pub paths: Vec<PathBuf>,
+    /// The songs have an album tag, so they get album gain even when the library has only one of them
+    pub is_album: bool,
+}
+
+/// A running analysis, which stops when cancelled or dropped
+pub struct AnalysisJob {
+    cancelled: Arc<AtomicBool>,
+    done: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    total: usize,
+    results: Receiver<AnalysisResult>,
+    finished: bool,
+}
+impl AnalysisJob {
+    /// Starts analysing the given albums, each a list of its songs' paths
+    /// Songs without an album can be passed as albums of one song, they get no album loudness
+    pub fn start(groups: Vec<AnalysisGroup>, write_tags: bool) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let total = groups.iter().map(|group| group.paths.len()).sum();
+        let (sender, results) = mpsc::channel();
+        let job = Worker {
+            cancelled: cancelled.clone(),
+            done: done.clone(),
+            failed: failed.clone(),
+            results: sender,
+            write_tags,
+        };
+        thread::spawn(move || job.run(groups));
+        Self {
+            cancelled,
+            done,
+            failed,
+            total,
+            results,
+            finished: false,
+        }
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    /// Songs analysed so far, and how many there are in total
+    pub fn progress(&self) -> (usize, usize) {
+        (self.done.load(Ordering::Relaxed), self.total)
+    }
+    /// Songs that couldn't be decoded, their albums are measured without them
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+    /// Returns the results that came in since the last call
+    pub fn poll(&mut self) -> Vec<AnalysisResult> {
+        let mut results = vec![];
+        loop {
+            match self.results.try_recv() {
+                Ok(result) => results.push(result),
+                Err(mpsc::TryRecvError::Empty) => break,
+                // The worker is done once it drops its sender
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                }
+            }
+        }
+        results
+    }
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+impl Drop for AnalysisJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+struct Worker {
+    cancelled: Arc<AtomicBool>,
+    done: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    results: Sender<AnalysisResult>,
+    write_tags: bool,
+}
+impl Worker {
+    fn run(self, groups: Vec<AnalysisGroup>) {
+        for AnalysisGroup { paths, is_album } in groups {
+            let mut tracks = vec![];
+            let mut album_blocks = vec![];
+            let mut album_peak = 0f64;
+            for path in paths {
+                let measured = measure(&path, &self.cancelled);
+                if self.cancelled.load(Ordering::Relaxed) {
+                    return;
+                }
+                self.done.fetch_add(1, Ordering::Relaxed);
+                match measured {
+                    Ok((loudness, blocks)) => {
+                        album_blocks.extend(blocks);
+                        album_peak = album_peak.max(loudness.true_peak);
+                        tracks.push((path, loudness));
+                    }
+                    // The album is measured from the songs that could be decoded
+                    Err(error) => {
+                        eprintln!("Could not analyse {:?}: {}", path, error);
+                        self.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            let album_loudness = integrated_loudness(&album_blocks);
+            for (path, loudness) in tracks {
+                let loudness = AnalysedLoudness {
+                    track_loudness: loudness.integrated.map(|lufs| lufs as f32),
+                    track_peak: loudness.true_peak as f32,
+                    album_loudness: album_loudness.filter(|_| is_album).map(|lufs| lufs as f32),
+                    album_peak: is_album.then_some(album_peak as f32),
+                };
+                let written = self.write_tags
+                    && match write_tags(&path, &loudness) {
+                        Ok(()) => true,
+                        Err(error) => {
+                            eprintln!("Could not write ReplayGain tags to {:?}: {}", path, error);
+                            false
+                        }
+                    };
+                let result = AnalysisResult {
+                    path,
+                    loudness,
+                    written,
+                };
+                if self.results.send(result).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AnalysisError {
+    Open(std::io::Error),
+    Decode(SymphoniaError),
+    NoAudio,
+}
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::Open(error) => write!(f, "Could not open file: {}", error),
+            AnalysisError::Decode(error) => write!(f, "Could not decode file: {}", error),
+            AnalysisError::NoAudio => write!(f, "The file has no audio track"),
+        }
+    }
+}
+
+/// Decodes the whole file, returning its loudness and gating blocks
+fn measure(path: &Path, cancelled: &AtomicBool) -> Result<(Loudness, Vec<f64>), AnalysisError> {
+    let file = File::open(path).map_err(AnalysisError::Open)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(AnalysisError::Decode)?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or(AnalysisError::NoAudio)?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(AnalysisError::Decode)?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    while !cancelled.load(Ordering::Relaxed) {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // The end of the file
+            Err(SymphoniaError::IoError(error))
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(error) => return Err(AnalysisError::Decode(error)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet, skip it like playback would
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(error) => return Err(AnalysisError::Decode(error)),
+        };
+        let spec = *decoded.spec();
+        let meter =
+            meter.get_or_insert_with(|| LoudnessMeter::new(spec.channels.count(), spec.rate));
+        let samples = match &mut samples {
+            Some(samples) if samples.capacity() >= decoded.capacity() * spec.channels.count() => {
+                samples
+            }
+            samples => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        samples.copy_interleaved_ref(decoded);
+        meter.add_samples(samples.samples());
+    }
+    meter
+        .map(LoudnessMeter::finish)
+        .ok_or(AnalysisError::NoAudio)
+}
+
+/// Writes the result as REPLAYGAIN_* tags, in the file's main tag
+fn write_tags(path: &Path, loudness: &AnalysedLoudness) -> lofty::error::Result<()> {
+    let mut file = lofty::read_from_path(path)?;
+    if file.primary_tag().is_none() {
+        let tag_type = file.primary_tag_type();
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let Some(tag) = file.primary_tag_mut() else {
+        return Ok(());
+    };
+    let replay_gain = loudness.replay_gain();
+    let gain = |gain: f32| format!("{:.2} dB", gain);
+    let peak = |peak: f32| format!("{:.6}", peak);
+    let values = [
+        (
+            ItemKey::ReplayGainTrackGain,
+            replay_gain.track_gain.map(gain),
+        ),
+        (
+            ItemKey::ReplayGainTrackPeak,
+            replay_gain.track_peak.map(peak),
+        ),
+        (
+            ItemKey::ReplayGainAlbumGain,
+            replay_gain.album_gain.map(gain),
+        ),
+        (
+            ItemKey::ReplayGainAlbumPeak,
+            replay_gain.album_peak.map(peak),
+        ),
+    ];
+    for (key, text) in values {
+        if let Some(text) = text {
+            tag.insert_text(key, text);
+        }
+    }
+    tag.save_to_path(path, WriteOptions::default())
+}
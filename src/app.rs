@@ -1,6 +1,12 @@
+use crate::analysis::{AnalysisJob, AnalysisMessage};
+use crate::config::ConfigState;
 use crate::page::*;
 use crate::player;
 use crate::player::Player;
+use crate::search;
+use crate::search::{Search, SearchMessage};
+use crate::song::Song;
+use crate::song_library::SongLibrary;
 use cosmic::iced::futures::SinkExt;
 use cosmic::iced::time::Duration;
 use cosmic::iced_futures;
@@ -10,12 +16,19 @@ extern crate tokio;
 // use crate::song::Song;
 use albums_page::AlbumsPage;
 use cosmic;
+use cosmic::iced::Length;
 use cosmic::iced::Subscription;
+use cosmic::iced::keyboard::{self, Key, Modifiers};
+use cosmic::iced::mouse::{self, ScrollDelta};
+use cosmic::iced::{Event, event};
 use cosmic::widget::nav_bar;
 use cosmic::widget::pane_grid;
 use cosmic::widget::pane_grid::Axis;
+use cosmic::widget::{Toast, ToastId, Toasts, toaster};
 use std::env;
 use std::path;
+use std::path::PathBuf;
+use tracks_page::TracksPage;
 extern crate rodio;
 
 #[derive(Debug, Clone)]
@@ -23,7 +36,28 @@ pub enum Message {
     FilesPage(files_page::FilesPageMessage),
     AlbumsPage(albums_page::AlbumsPageMessage),
     ArtistsPage(artists_page::ArtistsPageMessage),
+    TracksPage(tracks_page::TracksPageMessage),
     Player(player::PlayerMessage),
+    Search(SearchMessage),
+    Populate(Option<Song>), // Adds a song found while scanning the music directory
+    SetRating(PathBuf, Option<u8>), // Sets or clears the rating of the song at the path
+    MergeAlbums(Vec<String>), // Shows the albums, by key, as one under the first album
+    UnmergeAlbum(String),   // Splits albums merged into the one with this key back out
+    SetSortArticles(Vec<String>), // Changes the leading words ignored when sorting, e.g. "The"
+    ModifiersChanged(Modifiers),
+    WheelScrolled(ScrollDelta),
+    Zoom(f32),        // Ctrl+scroll, in wheel lines, positive zooms in
+    TypeToFind(char), // A letter typed outside of any text input, jumps through the page's list
+    Analysis(AnalysisMessage),
+    CloseToast(ToastId),
+}
+
+/// Pages that can be selected from the nav bar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NavPage {
+    Albums,
+    Tracks,
+    Artists,
 }
 
 enum Pane {
@@ -37,6 +71,17 @@ pub struct App {
     nav_bar: nav_bar::Model,
     pane_state: pane_grid::State<Pane>,
     pub player: Player,
+    library: SongLibrary,
+    config: ConfigState,
+    search: Search,
+    /// Keyboard modifiers currently held, for Ctrl+scroll zooming
+    modifiers: Modifiers,
+    /// The loudness analysis, while it runs
+    analysis: Option<AnalysisJob>,
+    /// Errors shown over the window, like songs that were skipped
+    toasts: Toasts<Message>,
+    /// The library generation the page was last brought up to date with
+    shown_generation: u64,
 }
 
 impl cosmic::Application for App {
@@ -53,11 +98,17 @@ impl cosmic::Application for App {
         nav_bar
             .insert()
             .text("Albums")
-            // .data::<Box<dyn Page>>(Box::new(AlbumsPage::default()))
-            // idk what im doing this prob needs to be refactored when i do ^
+            .data::<NavPage>(NavPage::Albums)
             // .icon(icon::from_name("applications-science-symbolic"))
             .activate();
-        nav_bar.insert().text("Artists");
+        nav_bar
+            .insert()
+            .text("Tracks")
+            .data::<NavPage>(NavPage::Tracks);
+        nav_bar
+            .insert()
+            .text("Artists")
+            .data::<NavPage>(NavPage::Artists);
 
         let music_dir: path::PathBuf;
         if let Some(mut home_dir) = env::home_dir() {
@@ -67,20 +118,41 @@ impl cosmic::Application for App {
             // Slow and kind of stupid
             music_dir = path::PathBuf::from("/");
         }
-        let (albums_page, task) = AlbumsPage::new(&music_dir).expect("Could not find albums: ");
+        let task = cosmic::Task::batch(SongLibrary::populate(music_dir))
+            .map(|song| cosmic::Action::App(Message::Populate(song)));
         // Initialize pane state
         let (mut pane_state, pane) = pane_grid::State::new(Pane::Content);
         pane_state.split(Axis::Vertical, pane, Pane::Player);
 
-        // let player = Player::default();
-        let app = Self {
-            page: Box::new(albums_page),
+        let config = ConfigState::load(Self::APP_ID);
+        let mut player = Player::default();
+        player.set_volume(config.config.volume);
+        player.set_muted(config.config.muted);
+        player.set_replay_gain(config.config.replay_gain);
+        player.set_equalizer(
+            config.config.equalizer.clone(),
+            config.config.equalizer_presets.clone(),
+        );
+        player.set_crossfade(config.config.crossfade);
+        player.set_speed(config.config.speed);
+        player.set_channels(config.config.channels);
+        let library = SongLibrary::load(Self::APP_ID, config.config.sort_articles.clone());
+        let mut app = Self {
+            page: Box::new(AlbumsPage::new(&config, &library)),
             nav_bar,
             core,
             pane_state,
-            player: Player::default(),
+            player,
+            library,
+            config,
+            search: Search::default(),
+            modifiers: Modifiers::default(),
+            analysis: None,
+            toasts: Toasts::new(Message::CloseToast),
+            shown_generation: 0,
         };
-        (app, task)
+        let toasts = app.show_player_errors();
+        (app, cosmic::Task::batch([task, toasts]))
     }
     fn core(&self) -> &cosmic::Core {
         &self.core
@@ -90,34 +162,33 @@ impl cosmic::Application for App {
     }
     // View the state of the application
     fn view(&self) -> cosmic::Element<'_, Message> {
-        pane_grid(&self.pane_state, |_pane, state, _is_maximized| {
+        let panes = pane_grid(&self.pane_state, |_pane, state, _is_maximized| {
             pane_grid::Content::new(match state {
                 Pane::Player => self.player.view(),
-                Pane::Content => self.page.view(),
+                // Search results take over the content pane while there is a query
+                Pane::Content if self.search.is_active() => {
+                    search::view(&self.library, &self.search)
+                }
+                Pane::Content => self.page.view(&self.library),
             })
-        })
-        .into()
+        });
+        toaster(&self.toasts, panes)
     }
     // Update the state of the application with messages from view
     fn update(&mut self, message: Message) -> cosmic::Task<cosmic::Action<Message>> {
-        match message {
-            Message::Player(player_message) => {
-                self.player.update(player_message);
-                return cosmic::Task::none();
-            }
-            _ => {
-                let page = self.page.update(message);
-                if let (task, Some(p)) = page {
-                    self.page = p;
-                    return task;
-                }
-            }
+        // Songs found while scanning come in quick succession, the page and search catch up with them on the next tick
+        let populating = matches!(message, Message::Populate(_));
+        let task = self.handle(message);
+        if !populating && self.library.generation() != self.shown_generation {
+            self.shown_generation = self.library.generation();
+            self.search.refresh(&self.library);
+            return cosmic::Task::batch([task, self.page.library_changed(&self.library)]);
         }
-        cosmic::Task::none()
+        task
     }
     /// Subscription, primarily for updating the song progress bar as time passes
     fn subscription(&self) -> Subscription<Message> {
-        return Subscription::run(|| {
+        let progress = Subscription::run(|| {
             iced_futures::stream::channel(1, |mut emitter| async move {
                 let mut interval = tokio::time::interval(Duration::from_millis(100));
 
@@ -127,6 +198,110 @@ impl cosmic::Application for App {
                 }
             })
         });
+        // Track held modifiers and wheel movement for Ctrl+scroll zooming, typing to find,
+        // and Ctrl+Up, Ctrl+Down and Ctrl+M for the volume
+        let input = event::listen_with(|event, status, _window| match event {
+            Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) => {
+                Some(Message::ModifiersChanged(modifiers))
+            }
+            Event::Keyboard(keyboard::Event::KeyPressed { key, modifiers, .. })
+                if status == event::Status::Ignored && modifiers.control() =>
+            {
+                let player_message = match key.as_ref() {
+                    Key::Named(keyboard::key::Named::ArrowUp) => PlayerMessage::ChangeVolume(1.0),
+                    Key::Named(keyboard::key::Named::ArrowDown) => {
+                        PlayerMessage::ChangeVolume(-1.0)
+                    }
+                    Key::Character("m") => PlayerMessage::ToggleMute,
+                    _ => return None,
+                };
+                Some(Message::Player(player_message))
+            }
+            Event::Keyboard(keyboard::Event::KeyPressed {
+                text: Some(text),
+                modifiers,
+                ..
+            }) if status == event::Status::Ignored
+                && !modifiers.control()
+                && !modifiers.alt()
+                && !modifiers.logo() =>
+            {
+                text.chars()
+                    .next()
+                    .filter(|c| !c.is_control())
+                    .map(Message::TypeToFind)
+            }
+            Event::Mouse(mouse::Event::WheelScrolled { delta }) => {
+                Some(Message::WheelScrolled(delta))
+            }
+            _ => None,
+        });
+        // Pick up the loudness analysis' results while it runs
+        let analysis = match self.analysis {
+            Some(_) => Subscription::run(|| {
+                iced_futures::stream::channel(1, |mut emitter| async move {
+                    let mut interval = tokio::time::interval(Duration::from_millis(250));
+
+                    loop {
+                        interval.tick().await;
+                        _ = emitter.send(Message::Analysis(AnalysisMessage::Poll)).await;
+                    }
+                })
+            }),
+            None => Subscription::none(),
+        };
+        Subscription::batch([progress, input, analysis])
+    }
+    /// Loudness analysis controls and the library search field
+    fn header_end(&self) -> Vec<cosmic::Element<'_, Message>> {
+        let spacing = cosmic::theme::spacing().space_s;
+        let analysis: cosmic::Element<'_, Message> = match &self.analysis {
+            Some(job) => {
+                let (done, total) = job.progress();
+                let mut status = format!("Analysing loudness {} / {}", done, total);
+                let failed = job.failed();
+                if failed > 0 {
+                    status.push_str(&format!(", {} failed", failed));
+                }
+                cosmic::widget::row::with_capacity(3)
+                    .push(
+                        cosmic::widget::container(cosmic::widget::progress_bar(
+                            0.0..=total as f32,
+                            done as f32,
+                        ))
+                        .width(Length::Fixed(120.0)),
+                    )
+                    .push(cosmic::widget::text(status))
+                    .push(
+                        cosmic::widget::button::text("Cancel")
+                            .on_press(Message::Analysis(AnalysisMessage::Cancel)),
+                    )
+                    .align_y(cosmic::iced::Alignment::Center)
+                    .spacing(spacing)
+                    .into()
+            }
+            None => cosmic::widget::row::with_capacity(2)
+                .push(
+                    cosmic::widget::toggler(self.config.config.analysis_write_tags)
+                        .label("Write tags")
+                        .on_toggle(|_| Message::Analysis(AnalysisMessage::ToggleWriteTags)),
+                )
+                .push(
+                    cosmic::widget::button::text("Analyse loudness")
+                        .on_press(Message::Analysis(AnalysisMessage::Start)),
+                )
+                .align_y(cosmic::iced::Alignment::Center)
+                .spacing(spacing)
+                .into(),
+        };
+        vec![
+            analysis,
+            cosmic::widget::search_input("Search library", &self.search.query)
+                .on_input(|query| Message::Search(SearchMessage::Query(query)))
+                .on_clear(Message::Search(SearchMessage::Query(String::new())))
+                .width(Length::Fixed(300.0))
+                .into(),
+        ]
     }
     /// Enable the nav bar to appear in your application when `Some`.
     fn nav_model(&self) -> Option<&nav_bar::Model> {
@@ -137,6 +312,174 @@ impl cosmic::Application for App {
     fn on_nav_select(&mut self, id: nav_bar::Id) -> cosmic::Task<cosmic::Action<Message>> {
         // Activate the page in the model.
         self.nav_bar.activate(id);
+        match self.nav_bar.data::<NavPage>(id).copied() {
+            Some(NavPage::Albums) => {
+                self.page = Box::new(AlbumsPage::new(&self.config, &self.library))
+            }
+            Some(NavPage::Tracks) => {
+                self.page = Box::new(TracksPage::new(&self.config, &self.library))
+            }
+            // No artists page yet
+            Some(NavPage::Artists) | None => {}
+        }
+        cosmic::Task::none()
+    }
+}
+
+impl App {
+    /// Handles a message, the page is told about library changes afterwards in update
+    fn handle(&mut self, message: Message) -> cosmic::Task<cosmic::Action<Message>> {
+        match message {
+            Message::Player(player_message) => {
+                // Slider drags are saved once the slider is let go
+                let save_settings = match &player_message {
+                    PlayerMessage::Equalizer(message) => !message.is_drag(),
+                    message => matches!(
+                        message,
+                        PlayerMessage::SaveSettings
+                            | PlayerMessage::ChangeVolume(_)
+                            | PlayerMessage::ToggleMute
+                            | PlayerMessage::SetReplayGainMode(_)
+                            | PlayerMessage::TogglePreventClipping
+                            | PlayerMessage::SetCrossfadeCurve(_)
+                            | PlayerMessage::SetSpeedMode(_)
+                            | PlayerMessage::ToggleMono
+                            | PlayerMessage::ToggleSwapChannels
+                            | PlayerMessage::ToggleCrossfeed
+                            | PlayerMessage::SetCrossfeedLevel(_)
+                    ),
+                };
+                self.player.update(player_message, &self.library);
+                if save_settings {
+                    self.config.config.volume = self.player.volume();
+                    self.config.config.muted = self.player.is_muted();
+                    self.config.config.replay_gain = self.player.replay_gain();
+                    let (equalizer, presets) = self.player.equalizer();
+                    self.config.config.equalizer = equalizer.clone();
+                    self.config.config.equalizer_presets = presets.to_vec();
+                    self.config.config.crossfade = self.player.crossfade();
+                    self.config.config.speed = self.player.speed();
+                    self.config.config.channels = self.player.channels();
+                    self.config.save();
+                }
+                // Only songs that actually started count, not ones skipped past before playing
+                for path in self.player.take_started() {
+                    self.library.record_play(&path);
+                }
+                return self.show_player_errors();
+            }
+            Message::CloseToast(id) => {
+                self.toasts.remove(id);
+            }
+            Message::Populate(song) => {
+                if let Some(song) = song {
+                    self.library.add_song(song);
+                }
+            }
+            Message::Search(search_message) => {
+                if let Some(player_message) =
+                    search::update(&mut self.search, &self.library, search_message)
+                {
+                    return cosmic::Task::done(cosmic::Action::App(Message::Player(
+                        player_message,
+                    )));
+                }
+            }
+            Message::SetRating(path, rating) => {
+                self.library.set_rating(&path, rating);
+            }
+            Message::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers;
+                // Pages need them too, so Ctrl+scroll zooms without scrolling them
+                let (task, _) = self.page.update(message, &self.library, &mut self.config);
+                return task;
+            }
+            Message::WheelScrolled(delta) => {
+                if self.modifiers.control() {
+                    let lines = match delta {
+                        ScrollDelta::Lines { y, .. } => y,
+                        // Roughly how far a touchpad moves for one wheel line
+                        ScrollDelta::Pixels { y, .. } => y / 40.0,
+                    };
+                    return cosmic::Task::done(cosmic::Action::App(Message::Zoom(lines)));
+                }
+            }
+            // Typing goes to the search field instead while it's in use
+            Message::TypeToFind(_) if self.search.is_active() => {}
+            Message::Analysis(analysis_message) => match analysis_message {
+                AnalysisMessage::Start => {
+                    let albums = self.library.songs_to_analyse();
+                    if self.analysis.is_none() && !albums.is_empty() {
+                        self.analysis = Some(AnalysisJob::start(
+                            albums,
+                            self.config.config.analysis_write_tags,
+                        ));
+                    }
+                }
+                AnalysisMessage::Cancel => {
+                    if let Some(job) = self.analysis.as_ref() {
+                        job.cancel();
+                    }
+                }
+                AnalysisMessage::Poll => {
+                    let Some(job) = self.analysis.as_mut() else {
+                        return cosmic::Task::none();
+                    };
+                    self.library.set_loudness(job.poll());
+                    if job.is_finished() {
+                        let failed = job.failed();
+                        self.analysis = None;
+                        if failed > 0 {
+                            return self
+                                .toasts
+                                .push(Toast::new(format!(
+                                    "Could not analyse {} {}, their albums were measured without them",
+                                    failed,
+                                    if failed == 1 { "song" } else { "songs" }
+                                )))
+                                .map(cosmic::Action::App);
+                        }
+                    }
+                }
+                AnalysisMessage::ToggleWriteTags => {
+                    self.config.config.analysis_write_tags =
+                        !self.config.config.analysis_write_tags;
+                    self.config.save();
+                }
+            },
+            Message::MergeAlbums(albums) => {
+                self.library.merge_albums(&albums);
+            }
+            Message::UnmergeAlbum(album) => {
+                self.library.unmerge_album(&album);
+            }
+            Message::SetSortArticles(articles) => {
+                self.config.config.sort_articles = articles.clone();
+                self.config.save();
+                self.library.set_articles(articles);
+            }
+            _ => {
+                let (task, page) = self.page.update(message, &self.library, &mut self.config);
+                if let Some(p) = page {
+                    self.page = p;
+                }
+                return task;
+            }
+        }
         cosmic::Task::none()
     }
+    /// Shows what went wrong in the player since the last call as toasts
+    fn show_player_errors(&mut self) -> cosmic::Task<cosmic::Action<Message>> {
+        let toasts: Vec<_> = self
+            .player
+            .take_errors()
+            .into_iter()
+            .map(|error| {
+                self.toasts
+                    .push(Toast::new(error.to_string()))
+                    .map(cosmic::Action::App)
+            })
+            .collect();
+        cosmic::Task::batch(toasts)
+    }
 }
@@ -0,0 +1,95 @@
+//! Settings for how the left and right channels are mixed, and the crossfeed filter they use
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Crossfeed strengths from the bs2b library, for a speaker-like sound on headphones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CrossfeedLevel {
+    /// The closest to speakers
+    #[default]
+    Default,
+    /// Chu Moy's circuit, a little stronger
+    ChuMoy,
+    /// Jan Meier's circuit, the strongest
+    JanMeier,
+}
+impl CrossfeedLevel {
+    pub const ALL: [CrossfeedLevel; 3] = [
+        CrossfeedLevel::Default,
+        CrossfeedLevel::ChuMoy,
+        CrossfeedLevel::JanMeier,
+    ];
+    pub const LABELS: [&'static str; 3] = ["Default", "Chu Moy", "Jan Meier"];
+    /// Cut frequency in Hz and how much of the other channel is fed across, in dB
+    fn parameters(self) -> (f64, f64) {
+        match self {
+            CrossfeedLevel::Default => (700.0, 4.5),
+            CrossfeedLevel::ChuMoy => (700.0, 6.0),
+            CrossfeedLevel::JanMeier => (650.0, 9.5),
+        }
+    }
+    /// Bauer's crossfeed as in bs2b: the other channel low passed, and this one high boosted to match
+    pub fn coefficients(self, sample_rate: u32) -> CrossfeedCoefficients {
+        let rate = f64::from(sample_rate);
+        let (cut, feed) = self.parameters();
+        let low_gain_db = feed * -5.0 / 6.0 - 3.0;
+        let high_gain_db = feed / 6.0 - 3.0;
+        let low_gain = 10f64.powf(low_gain_db / 20.0);
+        let high_gain = 1.0 - 10f64.powf(high_gain_db / 20.0);
+        let high_cut = cut * 2f64.powf((low_gain_db - 20.0 * high_gain.log10()) / 12.0);
+
+        let x = (-2.0 * PI * cut / rate).exp();
+        let low = (low_gain * (1.0 - x), x);
+        let x = (-2.0 * PI * high_cut / rate).exp();
+        let high = (1.0 - high_gain * (1.0 - x), -x, x);
+        CrossfeedCoefficients {
+            low,
+            high,
+            gain: 1.0 / (1.0 - high_gain + low_gain),
+        }
+    }
+}
+
+/// One pole filter coefficients of the crossfeed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrossfeedCoefficients {
+    /// Low pass for the other channel: a0, b1
+    pub low: (f64, f64),
+    /// High shelf for the channel itself: a0, a1, b1
+    pub high: (f64, f64, f64),
+    /// Makes up for the level the filters add
+    pub gain: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChannelSettings {
+    /// From -1 for only the left channel to 1 for only the right one
+    pub balance: f32,
+    /// Both channels play the same mix of the two
+    pub mono: bool,
+    pub swap: bool,
+    pub crossfeed: bool,
+    pub crossfeed_level: CrossfeedLevel,
+}
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            balance: 0.0,
+            mono: false,
+            swap: false,
+            crossfeed: false,
+            crossfeed_level: CrossfeedLevel::Default,
+        }
+    }
+}
+impl ChannelSettings {
+    /// Whether any of the settings changes the sound
+    pub fn is_active(&self) -> bool {
+        self.balance != 0.0 || self.mono || self.swap || self.crossfeed
+    }
+    /// Gains of the left and right channels, the side turned towards stays at full volume
+    pub fn balance_gains(&self) -> (f32, f32) {
+        let balance = self.balance.clamp(-1.0, 1.0);
+        ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0))
+    }
+}
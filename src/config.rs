@@ -0,0 +1,91 @@
+use crate::channel_tools::ChannelSettings;
+use crate::crossfade::CrossfadeSettings;
+use crate::equalizer::{EqualizerPreset, EqualizerSettings};
+use crate::page::albums_page::{AlbumGrouping, AlbumSort, DEFAULT_CARD_WIDTH};
+use crate::page::tracks_page::TrackColumn;
+use crate::replay_gain::ReplayGainSettings;
+use crate::sort_key::DEFAULT_ARTICLES;
+use crate::speed::SpeedSettings;
+use cosmic::cosmic_config::{self, CosmicConfigEntry, cosmic_config_derive::CosmicConfigEntry};
+
+/// Persistent user settings, stored through cosmic-config
+#[derive(Debug, Clone, CosmicConfigEntry, PartialEq)]
+#[version = 1]
+pub struct Config {
+    /// Visible columns of the tracks table, in display order
+    pub tracks_columns: Vec<TrackColumn>,
+    pub tracks_sort: TrackColumn,
+    pub tracks_sort_ascending: bool,
+    pub albums_sort: AlbumSort,
+    pub albums_sort_ascending: bool,
+    pub albums_grouping: AlbumGrouping,
+    /// Width of the album cards on the albums page
+    pub albums_card_width: f32,
+    /// Leading words ignored when sorting names, e.g. "The" in "The Beatles", set on the tracks page
+    pub sort_articles: Vec<String>,
+    /// Position of the player's volume slider, from 0 to 1
+    pub volume: f32,
+    pub muted: bool,
+    pub replay_gain: ReplayGainSettings,
+    /// Write the results of loudness analysis to the files as ReplayGain tags
+    pub analysis_write_tags: bool,
+    pub equalizer: EqualizerSettings,
+    /// The user's own equalizer presets
+    pub equalizer_presets: Vec<EqualizerPreset>,
+    pub crossfade: CrossfadeSettings,
+    pub speed: SpeedSettings,
+    pub channels: ChannelSettings,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tracks_columns: TrackColumn::DEFAULT.to_vec(),
+            tracks_sort: TrackColumn::Title,
+            tracks_sort_ascending: true,
+            albums_sort: AlbumSort::Title,
+            albums_sort_ascending: true,
+            albums_grouping: AlbumGrouping::None,
+            albums_card_width: DEFAULT_CARD_WIDTH,
+            sort_articles: DEFAULT_ARTICLES.map(String::from).to_vec(),
+            volume: 1.0,
+            muted: false,
+            replay_gain: ReplayGainSettings::default(),
+            analysis_write_tags: false,
+            equalizer: EqualizerSettings::default(),
+            equalizer_presets: vec![],
+            crossfade: CrossfadeSettings::default(),
+            speed: SpeedSettings::default(),
+            channels: ChannelSettings::default(),
+        }
+    }
+}
+
+/// The loaded config along with the handle used to write it back to disk
+pub struct ConfigState {
+    handler: Option<cosmic_config::Config>,
+    pub config: Config,
+}
+impl ConfigState {
+    pub fn load(app_id: &str) -> Self {
+        let handler = cosmic_config::Config::new(app_id, Config::VERSION).ok();
+        let config = handler
+            .as_ref()
+            .map(|handler| match Config::get_entry(handler) {
+                Ok(config) => config,
+                // Fall back to defaults for any keys that could not be read
+                Err((_errors, config)) => config,
+            })
+            .unwrap_or_default();
+
+        Self { handler, config }
+    }
+    /// Writes the current config to disk
+    pub fn save(&self) {
+        let Some(handler) = self.handler.as_ref() else {
+            return;
+        };
+        if let Err(error) = self.config.write_entry(handler) {
+            eprintln!("Could not save config: {:#?}", error);
+        }
+    }
+}
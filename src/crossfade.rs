@@ -0,0 +1,59 @@
+//! How one song turns into the next
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+
+/// Longest crossfade the slider goes to, in seconds
+pub const MAX_CROSSFADE_SECONDS: f32 = 12.0;
+/// How long a song fades out for when skipped, instead of cutting off
+pub const SKIP_FADE: Duration = Duration::from_millis(250);
+
+/// The shape of the volume change while songs fade
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CrossfadeCurve {
+    /// Volumes change at a steady rate, which dips in the middle
+    Linear,
+    /// The total loudness stays the same throughout
+    #[default]
+    EqualPower,
+    /// Changes slowly at both ends and quickly in the middle
+    SCurve,
+}
+impl CrossfadeCurve {
+    pub const ALL: [CrossfadeCurve; 3] = [
+        CrossfadeCurve::Linear,
+        CrossfadeCurve::EqualPower,
+        CrossfadeCurve::SCurve,
+    ];
+    pub const LABELS: [&'static str; 3] = ["Linear", "Equal power", "S-curve"];
+    /// Gain of the song fading in, `progress` goes from 0 to 1 over the fade
+    /// The song fading out uses the gain at `1 - progress`
+    pub fn fade_in(self, progress: f32) -> f32 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            CrossfadeCurve::Linear => progress,
+            CrossfadeCurve::EqualPower => (progress * FRAC_PI_2).sin(),
+            CrossfadeCurve::SCurve => (1.0 - (progress * 2.0 * FRAC_PI_2).cos()) / 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CrossfadeSettings {
+    /// Overlap between songs in seconds, 0 plays them back to back
+    pub duration: f32,
+    pub curve: CrossfadeCurve,
+}
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            duration: 0.0,
+            curve: CrossfadeCurve::EqualPower,
+        }
+    }
+}
+impl CrossfadeSettings {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f32(self.duration.clamp(0.0, MAX_CROSSFADE_SECONDS))
+    }
+}
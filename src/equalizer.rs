@@ -0,0 +1,259 @@
+//! Equalizer settings and presets, and the biquad filters they are made of
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Centre frequencies of the graphic equalizer's bands, an octave apart
+pub const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// Bandwidth of the graphic bands, about an octave each
+const GRAPHIC_Q: f32 = 1.41;
+/// Range of band gains and the preamp in dB, either way
+pub const GAIN_RANGE_DB: f32 = 12.0;
+pub const MIN_FREQUENCY: f32 = 20.0;
+pub const MAX_FREQUENCY: f32 = 20000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EqualizerMode {
+    /// Fixed bands with adjustable gains
+    #[default]
+    Graphic,
+    /// Freely placed filters
+    Parametric,
+}
+impl EqualizerMode {
+    pub const ALL: [EqualizerMode; 2] = [EqualizerMode::Graphic, EqualizerMode::Parametric];
+    pub const LABELS: [&'static str; 2] = ["Graphic", "Parametric"];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterKind {
+    Peak,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+impl FilterKind {
+    pub const ALL: [FilterKind; 5] = [
+        FilterKind::Peak,
+        FilterKind::LowShelf,
+        FilterKind::HighShelf,
+        FilterKind::LowPass,
+        FilterKind::HighPass,
+    ];
+    pub const LABELS: [&'static str; 5] =
+        ["Peak", "Low shelf", "High shelf", "Low pass", "High pass"];
+    /// Pass filters have no gain
+    pub fn has_gain(self) -> bool {
+        !matches!(self, FilterKind::LowPass | FilterKind::HighPass)
+    }
+}
+
+/// One filter of the parametric equalizer
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ParametricBand {
+    pub kind: FilterKind,
+    /// Centre or corner frequency in Hz
+    pub frequency: f32,
+    /// In dB, unused by pass filters
+    pub gain: f32,
+    pub q: f32,
+}
+impl Default for ParametricBand {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::Peak,
+            frequency: 1000.0,
+            gain: 0.0,
+            q: 1.0,
+        }
+    }
+}
+impl ParametricBand {
+    /// Filter coefficients for the sample rate, normalised so a0 is 1: [b0, b1, b2, a1, a2]
+    /// Formulas from Robert Bristow-Johnson's Audio EQ Cookbook
+    pub fn coefficients(&self, sample_rate: u32) -> [f64; 5] {
+        let rate = f64::from(sample_rate);
+        // Filters at or past the Nyquist frequency are unstable
+        let frequency = f64::from(self.frequency).clamp(1.0, rate * 0.45);
+        let a = 10f64.powf(f64::from(self.gain) / 40.0);
+        let w0 = 2.0 * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * f64::from(self.q).max(0.01));
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let [b0, b1, b2, a0, a1, a2] = match self.kind {
+            FilterKind::Peak => [
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ],
+            FilterKind::LowShelf => [
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ],
+            FilterKind::HighShelf => [
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ],
+            FilterKind::LowPass => [
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+            FilterKind::HighPass => [
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+        };
+        [b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0]
+    }
+}
+
+/// The equalizer as the user set it up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EqualizerSettings {
+    /// Off bypasses the equalizer entirely
+    pub enabled: bool,
+    pub mode: EqualizerMode,
+    /// Gain applied before the filters in dB, to make room for boosts
+    pub preamp: f32,
+    /// Gains of the graphic bands in dB
+    pub graphic: [f32; 10],
+    pub parametric: Vec<ParametricBand>,
+    /// The preset the settings came from, cleared once they are changed
+    pub preset: Option<String>,
+}
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: EqualizerMode::Graphic,
+            preamp: 0.0,
+            graphic: [0.0; 10],
+            parametric: vec![],
+            preset: None,
+        }
+    }
+}
+impl EqualizerSettings {
+    /// The filters to run the audio through, an empty list for none
+    pub fn bands(&self) -> Vec<ParametricBand> {
+        if !self.enabled {
+            return vec![];
+        }
+        match self.mode {
+            EqualizerMode::Graphic => GRAPHIC_FREQUENCIES
+                .iter()
+                .zip(self.graphic)
+                // Flat bands don't change anything
+                .filter(|(_, gain)| *gain != 0.0)
+                .map(|(frequency, gain)| ParametricBand {
+                    kind: FilterKind::Peak,
+                    frequency: *frequency,
+                    gain,
+                    q: GRAPHIC_Q,
+                })
+                .collect(),
+            EqualizerMode::Parametric => self.parametric.clone(),
+        }
+    }
+    /// The factor samples are multiplied by before filtering
+    pub fn preamp_factor(&self) -> f32 {
+        if !self.enabled {
+            return 1.0;
+        }
+        10f32.powf(self.preamp / 20.0)
+    }
+    pub fn apply(&mut self, preset: &EqualizerPreset) {
+        self.mode = preset.mode;
+        self.preamp = preset.preamp;
+        self.graphic = preset.graphic;
+        self.parametric = preset.parametric.clone();
+        self.preset = Some(preset.name.clone());
+    }
+}
+
+/// Named equalizer settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub mode: EqualizerMode,
+    pub preamp: f32,
+    pub graphic: [f32; 10],
+    pub parametric: Vec<ParametricBand>,
+}
+impl EqualizerPreset {
+    pub fn from_settings(name: String, settings: &EqualizerSettings) -> Self {
+        Self {
+            name,
+            mode: settings.mode,
+            preamp: settings.preamp,
+            graphic: settings.graphic,
+            parametric: settings.parametric.clone(),
+        }
+    }
+    /// Presets that come with the app, which can't be changed or deleted
+    pub fn built_in() -> Vec<EqualizerPreset> {
+        let graphic = |name: &str, preamp: f32, graphic: [f32; 10]| EqualizerPreset {
+            name: name.to_string(),
+            mode: EqualizerMode::Graphic,
+            preamp,
+            graphic,
+            parametric: vec![],
+        };
+        vec![
+            graphic("Flat", 0.0, [0.0; 10]),
+            graphic(
+                "Bass boost",
+                -6.0,
+                [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            ),
+            graphic(
+                "Treble boost",
+                -6.0,
+                [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 5.0, 6.0],
+            ),
+            graphic(
+                "Vocal",
+                -3.0,
+                [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0],
+            ),
+            graphic(
+                "Rock",
+                -4.0,
+                [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 1.0, 2.0, 3.0, 4.0],
+            ),
+            EqualizerPreset {
+                name: "Rumble filter".to_string(),
+                mode: EqualizerMode::Parametric,
+                preamp: 0.0,
+                graphic: [0.0; 10],
+                parametric: vec![ParametricBand {
+                    kind: FilterKind::HighPass,
+                    frequency: 30.0,
+                    gain: 0.0,
+                    q: 0.707,
+                }],
+            },
+        ]
+    }
+}
@@ -1,6 +1,20 @@
 const HEIGHT: u16 = 100;
+mod analysis;
 pub mod app;
+mod channel_tools;
+mod config;
+mod crossfade;
+mod equalizer;
+mod library_db;
+mod loudness;
 mod page;
 mod player;
+mod query;
+mod replay_gain;
+mod search;
 mod song;
 mod song_library;
+mod sort_key;
+mod speed;
+mod thumbnail;
+mod virtual_list;
@@ -0,0 +1,128 @@
+use crate::replay_gain::AnalysedLoudness;
+use cosmic::cosmic_config::{self, ConfigGet, ConfigSet};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const DB_VERSION: u64 = 1;
+const SONGS_KEY: &str = "songs";
+const ALBUM_MERGES_KEY: &str = "album_merges";
+
+/// Per-song data that is not stored in the file's tags
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SongStats {
+    pub play_count: u32,
+    pub last_played: Option<SystemTime>,
+    /// Rating out of 5
+    pub rating: Option<u8>,
+    /// Measured by the loudness analysis, for songs without ReplayGain tags
+    pub loudness: Option<AnalysedLoudness>,
+}
+
+/// Keeps song statistics keyed by file path, saved in the app's cosmic-config state directory
+#[derive(Debug)]
+pub struct LibraryDb {
+    handler: Option<cosmic_config::Config>,
+    songs: HashMap<PathBuf, SongStats>,
+    /// Album group keys that the user merged into another album's key
+    album_merges: HashMap<String, String>,
+}
+impl LibraryDb {
+    pub fn load(app_id: &str) -> Self {
+        let handler = cosmic_config::Config::new_state(app_id, DB_VERSION).ok();
+        let songs = handler
+            .as_ref()
+            .and_then(|handler| handler.get::<HashMap<PathBuf, SongStats>>(SONGS_KEY).ok())
+            .unwrap_or_default();
+        let album_merges = handler
+            .as_ref()
+            .and_then(|handler| {
+                handler
+                    .get::<HashMap<String, String>>(ALBUM_MERGES_KEY)
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        Self {
+            handler,
+            songs,
+            album_merges,
+        }
+    }
+    /// An empty database that is never written to disk
+    pub fn in_memory() -> Self {
+        Self {
+            handler: None,
+            songs: HashMap::new(),
+            album_merges: HashMap::new(),
+        }
+    }
+    pub fn stats(&self, path: &Path) -> Option<&SongStats> {
+        self.songs.get(path)
+    }
+    /// Counts a play of the song at the given path
+    pub fn record_play(&mut self, path: &Path) {
+        let stats = self.songs.entry(path.to_path_buf()).or_default();
+        stats.play_count += 1;
+        stats.last_played = Some(SystemTime::now());
+        self.save();
+    }
+    pub fn set_rating(&mut self, path: &Path, rating: Option<u8>) {
+        self.songs.entry(path.to_path_buf()).or_default().rating = rating.map(|r| r.min(5));
+        self.save();
+    }
+    /// Doesn't save, so a batch of results can be set and saved once
+    pub fn set_loudness(&mut self, path: &Path, loudness: AnalysedLoudness) {
+        self.songs.entry(path.to_path_buf()).or_default().loudness = Some(loudness);
+    }
+    /// Returns the album key the given album key was merged into, if any
+    pub fn merged_album(&self, key: &str) -> Option<&str> {
+        self.album_merges.get(key).map(|target| target.as_str())
+    }
+    /// Merges every album into the first one
+    pub fn merge_albums(&mut self, keys: &[String]) {
+        let Some((target, sources)) = keys.split_first() else {
+            return;
+        };
+        let target = self.merged_album(target).unwrap_or(target).to_string();
+        for source in sources {
+            if *source == target {
+                continue;
+            }
+            // Albums previously merged into this one follow it
+            for merged_target in self.album_merges.values_mut() {
+                if merged_target == source {
+                    *merged_target = target.clone();
+                }
+            }
+            self.album_merges.insert(source.clone(), target.clone());
+        }
+        self.save_album_merges();
+    }
+    /// Splits every album that was merged into the given one back out
+    pub fn unmerge_album(&mut self, key: &str) {
+        self.album_merges.retain(|_, target| target != key);
+        self.save_album_merges();
+    }
+    pub fn has_merges(&self, key: &str) -> bool {
+        self.album_merges.values().any(|target| target == key)
+    }
+    /// Writes every song's statistics, the whole map at once
+    pub fn save(&self) {
+        let Some(handler) = self.handler.as_ref() else {
+            return;
+        };
+        if let Err(error) = handler.set(SONGS_KEY, &self.songs) {
+            eprintln!("Could not save library database: {:#?}", error);
+        }
+    }
+    fn save_album_merges(&self) {
+        let Some(handler) = self.handler.as_ref() else {
+            return;
+        };
+        if let Err(error) = handler.set(ALBUM_MERGES_KEY, &self.album_merges) {
+            eprintln!("Could not save album merges: {:#?}", error);
+        }
+    }
+}
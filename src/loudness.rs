@@ -0,0 +1,299 @@
+//! EBU R128 loudness measurement: integrated loudness as in ITU-R BS.1770-4 and true peak by oversampling
+use std::f64::consts::PI;
+
+/// Blocks quieter than this are left out of the integrated loudness
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the loudness of the ungated blocks are left out as well
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// Gating blocks are 400ms long and start every 100ms
+const SUBBLOCKS_PER_BLOCK: usize = 4;
+const SUBBLOCKS_PER_SECOND: u32 = 10;
+/// True peak is looked for between samples by interpolating this many points per sample
+const OVERSAMPLING: usize = 4;
+/// Filter taps per interpolated point
+const TAPS_PER_PHASE: usize = 12;
+
+/// Measures the loudness of interleaved audio fed to it a chunk at a time
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    frames_per_subblock: usize,
+    /// Weighted energy and frames of the subblock being filled
+    subblock_energy: f64,
+    subblock_frames: usize,
+    /// The last few subblocks' mean energy, to make overlapping blocks from
+    recent_subblocks: Vec<f64>,
+    /// Mean energy of every gating block so far
+    blocks: Vec<f64>,
+    interpolation: Vec<[f64; TAPS_PER_PHASE]>,
+    peak: f64,
+}
+
+struct ChannelState {
+    weight: f64,
+    shelf: Biquad,
+    high_pass: Biquad,
+    /// Recent samples, newest first, for true peak interpolation
+    history: [f64; TAPS_PER_PHASE],
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let rate = f64::from(sample_rate);
+        Self {
+            channels: (0..channels)
+                .map(|channel| ChannelState {
+                    weight: channel_weight(channel, channels),
+                    shelf: Biquad::k_weighting_shelf(rate),
+                    high_pass: Biquad::k_weighting_high_pass(rate),
+                    history: [0.0; TAPS_PER_PHASE],
+                })
+                .collect(),
+            frames_per_subblock: (sample_rate / SUBBLOCKS_PER_SECOND).max(1) as usize,
+            subblock_energy: 0.0,
+            subblock_frames: 0,
+            recent_subblocks: Vec::with_capacity(SUBBLOCKS_PER_BLOCK),
+            blocks: vec![],
+            interpolation: interpolation_filter(),
+            peak: 0.0,
+        }
+    }
+    /// Adds interleaved samples, a whole number of frames
+    pub fn add_samples(&mut self, samples: &[f32]) {
+        let channel_count = self.channels.len();
+        if channel_count == 0 {
+            return;
+        }
+        for frame in samples.chunks_exact(channel_count) {
+            for (channel, sample) in self.channels.iter_mut().zip(frame) {
+                let sample = f64::from(*sample);
+                let filtered = channel.high_pass.process(channel.shelf.process(sample));
+                self.subblock_energy += channel.weight * filtered * filtered;
+
+                channel.history.copy_within(..TAPS_PER_PHASE - 1, 1);
+                channel.history[0] = sample;
+                self.peak = self.peak.max(sample.abs());
+                for taps in &self.interpolation {
+                    let interpolated: f64 = taps
+                        .iter()
+                        .zip(&channel.history)
+                        .map(|(tap, sample)| tap * sample)
+                        .sum();
+                    self.peak = self.peak.max(interpolated.abs());
+                }
+            }
+            self.subblock_frames += 1;
+            if self.subblock_frames == self.frames_per_subblock {
+                self.finish_subblock();
+            }
+        }
+    }
+    fn finish_subblock(&mut self) {
+        if self.recent_subblocks.len() == SUBBLOCKS_PER_BLOCK {
+            self.recent_subblocks.remove(0);
+        }
+        self.recent_subblocks
+            .push(self.subblock_energy / self.subblock_frames as f64);
+        self.subblock_energy = 0.0;
+        self.subblock_frames = 0;
+        if self.recent_subblocks.len() == SUBBLOCKS_PER_BLOCK {
+            let energy = self.recent_subblocks.iter().sum::<f64>() / SUBBLOCKS_PER_BLOCK as f64;
+            self.blocks.push(energy);
+        }
+    }
+    /// Returns the measurement of everything added, and the gating blocks for measuring an album
+    pub fn finish(self) -> (Loudness, Vec<f64>) {
+        let loudness = Loudness {
+            integrated: integrated_loudness(&self.blocks),
+            true_peak: self.peak,
+        };
+        (loudness, self.blocks)
+    }
+}
+
+/// The result of a measurement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// In LUFS, None when everything was below the absolute gate e.g. silence
+    pub integrated: Option<f64>,
+    /// Linear, where 1.0 is full scale
+    pub true_peak: f64,
+}
+
+/// Combines the gating blocks of several measurements, as an album is measured from all its tracks
+pub fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let loud_enough = |energy: &&f64, gate: f64| block_loudness(**energy) > gate;
+    let mean = |blocks: &[&f64]| {
+        (!blocks.is_empty()).then(|| blocks.iter().copied().sum::<f64>() / blocks.len() as f64)
+    };
+    let above_absolute: Vec<&f64> = blocks
+        .iter()
+        .filter(|energy| loud_enough(energy, ABSOLUTE_GATE_LUFS))
+        .collect();
+    let relative_gate = block_loudness(mean(&above_absolute)?) + RELATIVE_GATE_LU;
+    let above_relative: Vec<&f64> = above_absolute
+        .into_iter()
+        .filter(|energy| loud_enough(energy, relative_gate))
+        .collect();
+    mean(&above_relative).map(block_loudness)
+}
+
+fn block_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Surround channels count for more and the LFE channel isn't counted, for the usual 5.1 layout
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (5, 3 | 4) | (6, 4 | 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Windowed sinc filter split into the phases that each interpolate one point between samples
+fn interpolation_filter() -> Vec<[f64; TAPS_PER_PHASE]> {
+    let length = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (length - 1) as f64 / 2.0;
+    let tap = |i: usize| {
+        let x = (i as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / (length - 1) as f64).cos();
+        sinc * window
+    };
+    // The phase with the original samples is skipped, those are checked as they are
+    (1..OVERSAMPLING)
+        .map(|phase| std::array::from_fn(|k| tap(k * OVERSAMPLING + phase)))
+        .collect()
+}
+
+/// A second order IIR filter in direct form 1
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+impl Biquad {
+    /// The high shelf of the K-weighting filter, which models the head
+    fn k_weighting_shelf(rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+    /// The high pass of the K-weighting filter
+    fn k_weighting_high_pass(rate: f64) -> Self {
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Energy of a gating block with the given loudness
+    fn block(lufs: f64) -> f64 {
+        10f64.powf((lufs + 0.691) / 10.0)
+    }
+
+    fn assert_near(measured: Option<f64>, expected: f64) {
+        let measured = measured.expect("something above the gates");
+        assert!(
+            (measured - expected).abs() < 0.01,
+            "measured {measured}, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(integrated_loudness(&[]), None);
+        assert_eq!(integrated_loudness(&[0.0; 100]), None);
+        // Quiet but not silent, still below the absolute gate
+        assert_eq!(integrated_loudness(&[block(-75.0); 100]), None);
+    }
+
+    #[test]
+    fn absolute_gate_leaves_out_silence() {
+        // A quiet intro doesn't make the song measure quieter
+        let mut blocks = vec![0.0; 50];
+        blocks.extend([block(-80.0); 50]);
+        blocks.extend([block(-20.0); 100]);
+        assert_near(integrated_loudness(&blocks), -20.0);
+    }
+
+    #[test]
+    fn relative_gate_leaves_out_quiet_parts() {
+        // 15 LU down is below the gate, 10 LU under the mean of both
+        let mut blocks = vec![block(-20.0); 100];
+        blocks.extend([block(-35.0); 100]);
+        assert_near(integrated_loudness(&blocks), -20.0);
+
+        // 5 LU down is above it, and is averaged in by energy
+        let mut blocks = vec![block(-20.0); 100];
+        blocks.extend([block(-25.0); 100]);
+        let expected = -20.0 + 10.0 * ((1.0 + 10f64.powf(-0.5)) / 2.0).log10();
+        assert_near(integrated_loudness(&blocks), expected);
+    }
+
+    #[test]
+    fn sine_measures_its_level() {
+        // A 997 Hz sine at -20 dBFS in both channels measures -20 LUFS, as K-weighting is flat around 1 kHz
+        let rate = 48000;
+        let amplitude = 0.1;
+        let mut meter = LoudnessMeter::new(2, rate);
+        let samples: Vec<f32> = (0..rate * 5)
+            .flat_map(|frame| {
+                let phase = 2.0 * PI * 997.0 * f64::from(frame) / f64::from(rate);
+                let sample = (amplitude * phase.sin()) as f32;
+                [sample, sample]
+            })
+            .collect();
+        meter.add_samples(&samples);
+        let (loudness, _) = meter.finish();
+        let integrated = loudness.integrated.unwrap();
+        assert!((integrated - -20.0).abs() < 0.1, "measured {integrated}");
+        assert!((loudness.true_peak - amplitude).abs() < 0.01);
+
+        let mut meter = LoudnessMeter::new(2, rate);
+        meter.add_samples(&vec![0.0; rate as usize * 2 * 5]);
+        assert_eq!(meter.finish().0.integrated, None);
+    }
+}
@@ -1,8 +1,13 @@
 use crate::app::Message;
+use crate::config::ConfigState;
+use crate::query::Query;
+use crate::song_library::SongLibrary;
 use cosmic;
+use cosmic::widget::*;
 pub mod albums_page;
 pub mod artists_page;
 pub mod files_page;
+pub mod tracks_page;
 
 // pub enum Pages {
 //     AlbumsPage(albums_page::AlbumsPage),
@@ -10,12 +15,20 @@ pub mod files_page;
 //     FilesPage(files_page::FilesPage),
 // }
 
+/// A view of the library shown in the content pane
+/// The library and config are owned by the app and shared between pages
 pub trait Page {
     fn update(
         &mut self,
         message: Message,
+        library: &SongLibrary,
+        config: &mut ConfigState,
     ) -> (cosmic::Task<cosmic::Action<Message>>, Option<Box<dyn Page>>);
-    fn view(&self) -> cosmic::Element<'_, Message>;
+    fn view<'a>(&'a self, library: &'a SongLibrary) -> cosmic::Element<'a, Message>;
+    /// Rebuilds what the page keeps from the library, called once the library has changed
+    fn library_changed(&mut self, _library: &SongLibrary) -> cosmic::Task<cosmic::Action<Message>> {
+        cosmic::Task::none()
+    }
 }
 
 /// The style of containers for things like the album and song cards
@@ -27,3 +40,25 @@ pub fn card_style(_theme: &cosmic::Theme) -> cosmic::widget::container::Style {
         .border(cosmic::iced::Border::default().rounded(radius));
     container_style
 }
+
+/// Parses a page's filter text, returning None when there is nothing valid to filter by
+pub fn parse_filter(filter: &str) -> Option<Query> {
+    Query::parse(filter).ok().filter(|query| !query.is_empty())
+}
+
+/// A text input for filter queries, showing what is wrong with the query below it
+pub fn filter_input<'a>(
+    filter: &'a str,
+    on_input: impl Fn(String) -> Message + 'a,
+) -> cosmic::Element<'a, Message> {
+    let input = text_input(
+        "Filter, e.g. artist:\"Boards of Canada\" year:1995..2005",
+        filter,
+    )
+    .on_input(on_input);
+    let mut content = column::with_capacity(2).push(input);
+    if let Err(error) = Query::parse(filter) {
+        content = content.push(text::caption(error.to_string()));
+    }
+    content.into()
+}
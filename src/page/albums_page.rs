@@ -1,54 +1,314 @@
 // use crate::app::App;
+use crate::app::Message;
+use crate::config::ConfigState;
+use crate::library_db::SongStats;
 use crate::page::card_style;
-use crate::player::PlayerMessage;
-use crate::song_library::SongLibrary;
-use std::collections::HashMap;
-use std::collections::HashSet;
-extern crate rayon;
+use crate::page::{Page, filter_input, parse_filter};
+use crate::player::{DurationDisplayExt, PlayerMessage};
 use crate::song::Song;
-use cosmic::iced_core::text::Wrapping;
-use std::io::Write;
-extern crate walkdir;
-use crate::app::Message;
-use crate::page::Page;
+use crate::song_library::{Album, SongLibrary, album_title, most_common};
+use crate::sort_key::group_key;
+use crate::thumbnail::{self, ThumbnailKey};
+use crate::virtual_list::{self, ScrollState, TypeToFind, jump_letter};
 use cosmic;
 use cosmic::Element;
-// use cosmic::iced::Alignment;
+use cosmic::iced::Alignment;
+use cosmic::iced::ContentFit;
 use cosmic::iced::Length;
+use cosmic::iced::widget::scrollable::Viewport;
+use cosmic::iced_core::text::Wrapping;
 use cosmic::widget::*;
-use derivative::Derivative;
-use rayon::prelude::*;
-use std::collections::BTreeSet;
-use std::error;
-use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone)]
 pub enum AlbumsPageMessage {
-    ShowAlbum(String),
-    Populate(Option<Song>),
+    ShowAlbum(String), // By album key, see SongLibrary::album_key
     BackToAllAlbums,
-    PopulateAlbumsLibrary,
+    PlayAlbum(usize), // Plays the shown album from the track at the index
+    ShuffleAlbum,
+    QueueAlbum, // Adds the shown album to the end of the queue
+    PlayAlbumNext,
+    Filter(String), // Only shows albums with a song matching the filter query
+    SortBy(usize),  // Index into AlbumSort::ALL
+    ToggleSortDirection,
+    GroupBy(usize), // Index into AlbumGrouping::ALL
+    ToggleMergeMode,
+    SelectForMerge(String), // Adds or removes an album, by key, from the albums to merge
+    MergeSelected,          // Merges the selected albums into the first one selected
+    Scrolled(Viewport),
+    JumpTo(char), // Scrolls to the first album starting with the letter, '#' for anything else
+    CardWidth(f32), // Resizes the album cards while the zoom slider is dragged
+    SaveCardWidth, // Saves the card width once the zoom slider is released
+    ZoomStopped(u64), // Saves the card width if no Ctrl+scroll zoom came after this one
+    Thumbnail(ThumbnailKey, Option<image::Handle>), // A cover scaled down for the cards, None if it couldn't be
+}
+
+/// Card width before the user zooms, and the range they can zoom between
+pub const DEFAULT_CARD_WIDTH: f32 = 150.0;
+const MIN_CARD_WIDTH: f32 = 80.0;
+const MAX_CARD_WIDTH: f32 = 400.0;
+/// How much a wheel line of Ctrl+scroll changes the card width
+const CARD_ZOOM_STEP: f32 = 20.0;
+/// How long Ctrl+scroll has to stop for before the card width is saved
+const ZOOM_SAVE_DELAY: Duration = Duration::from_millis(500);
+/// Width of the A-Z strip next to the grid
+const JUMP_STRIP_WIDTH: f32 = 24.0;
+/// Rough size of a line of card label text, used to fit and truncate labels
+const LABEL_LINE_HEIGHT: f32 = 20.0;
+const LABEL_CHAR_WIDTH: f32 = 7.5;
+/// Height of the headers between groups of albums, including the gap below them
+const GROUP_HEADER_HEIGHT: f32 = 40.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlbumSort {
+    Title,
+    Artist,
+    Year,
+    DateAdded,
+    LastPlayed,
+    PlayCount,
+    Random,
+}
+impl AlbumSort {
+    const ALL: [AlbumSort; 7] = [
+        AlbumSort::Title,
+        AlbumSort::Artist,
+        AlbumSort::Year,
+        AlbumSort::DateAdded,
+        AlbumSort::LastPlayed,
+        AlbumSort::PlayCount,
+        AlbumSort::Random,
+    ];
+    const LABELS: [&'static str; 7] = [
+        "Title",
+        "Album Artist",
+        "Year",
+        "Date Added",
+        "Last Played",
+        "Play Count",
+        "Random",
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlbumGrouping {
+    None,
+    FirstLetter,
+    Artist,
+    Year,
+}
+impl AlbumGrouping {
+    const ALL: [AlbumGrouping; 4] = [
+        AlbumGrouping::None,
+        AlbumGrouping::FirstLetter,
+        AlbumGrouping::Artist,
+        AlbumGrouping::Year,
+    ];
+    const LABELS: [&'static str; 4] = ["No Grouping", "First Letter", "Album Artist", "Year"];
+}
+
+/// How the album grid is sorted and grouped
+pub struct AlbumOrder {
+    sort: AlbumSort,
+    ascending: bool,
+    grouping: AlbumGrouping,
+    /// Shuffles the random sort, changed every time random is picked
+    random_seed: u64,
+}
+impl AlbumOrder {
+    fn random_key(&self, album_key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.random_seed.hash(&mut hasher);
+        album_key.hash(&mut hasher);
+        hasher.finish()
+    }
 }
 
 pub struct AlbumsPage {
-    albums_library: SongLibrary,
+    /// The album shown instead of the grid
+    show_album: Option<AlbumDetail>,
+    filter: String,
+    order: AlbumOrder,
+    /// Keys of the albums picked to be merged while in merge mode, in the order they were picked
+    merge_selection: Option<Vec<String>>,
+    grid_scroll: ScrollState,
+    album_scroll: ScrollState,
+    card_width: f32,
+    type_to_find: TypeToFind,
+    grid: AlbumGrid,
+    /// Counts Ctrl+scroll zooms, so only the last of a burst saves the card width
+    zooms: u64,
+    /// Whether Ctrl is held, while it is the wheel zooms instead of scrolling the grid
+    ctrl_held: bool,
+    /// Covers scaled down for the cards, None while one is being made or if it couldn't be
+    thumbnails: HashMap<ThumbnailKey, Option<image::Handle>>,
 }
 
 impl AlbumsPage {
-    pub fn new(
-        music_dir: &Path,
-    ) -> Result<(AlbumsPage, cosmic::Task<cosmic::Action<Message>>), Box<dyn error::Error>> {
-        let albums = SongLibrary::default();
-        let populate_task = cosmic::Task::batch(SongLibrary::populate(music_dir.into()))
-            .map(|o| cosmic::Action::App(Message::AlbumsPage(AlbumsPageMessage::Populate(o))));
-
-        Ok((
-            AlbumsPage {
-                albums_library: albums,
-            },
-            populate_task,
-        ))
+    pub fn new(config: &ConfigState, library: &SongLibrary) -> AlbumsPage {
+        let order = AlbumOrder {
+            sort: config.config.albums_sort,
+            ascending: config.config.albums_sort_ascending,
+            grouping: config.config.albums_grouping,
+            random_seed: fastrand::u64(..),
+        };
+        let mut page = AlbumsPage {
+            show_album: None,
+            filter: String::new(),
+            grid: AlbumGrid::new(library, "", &order),
+            order,
+            merge_selection: None,
+            grid_scroll: ScrollState::default(),
+            album_scroll: ScrollState::default(),
+            card_width: config
+                .config
+                .albums_card_width
+                .clamp(MIN_CARD_WIDTH, MAX_CARD_WIDTH),
+            type_to_find: TypeToFind::default(),
+            zooms: 0,
+            ctrl_held: false,
+            thumbnails: HashMap::new(),
+        };
+        page.layout_grid();
+        page
+    }
+    /// Makes the grid again, after the library, filter or order changed
+    fn rebuild_grid(&mut self, library: &SongLibrary) {
+        self.grid = AlbumGrid::new(library, &self.filter, &self.order);
+        self.layout_grid();
+    }
+    /// Lays the grid out again, after the page or cards were resized
+    fn layout_grid(&mut self) {
+        self.grid
+            .layout(self.order.grouping, self.grid_scroll.width, self.card_width);
+        // Thumbnails made for another card size won't be drawn again until zoomed back
+        let size = thumbnail::size_for(self.grid.card.picture_size);
+        self.thumbnails.retain(|key, _| key.size() == size);
+    }
+    /// Starts making thumbnails for the covers in view that don't have one at the current card size
+    fn request_thumbnails(&mut self) -> cosmic::Task<cosmic::Action<Message>> {
+        if self.show_album.is_some() {
+            return cosmic::Task::none();
+        }
+        let size = thumbnail::size_for(self.grid.card.picture_size);
+        let mut tasks = vec![];
+        for row in self.grid_scroll.visible_with_heights(&self.grid.heights) {
+            let GridRow::Cards(cards) = &self.grid.rows[row] else {
+                continue;
+            };
+            for album in &self.grid.albums[cards.clone()] {
+                let key = ThumbnailKey::new(&album.picture, size);
+                if self.thumbnails.contains_key(&key) {
+                    continue;
+                }
+                self.thumbnails.insert(key, None);
+                let cover = album.picture.clone();
+                tasks.push(cosmic::Task::perform(
+                    async move {
+                        // Decoding is slow, keep it off the threads that run the app's futures
+                        tokio::task::spawn_blocking(move || thumbnail::make(&cover, size))
+                            .await
+                            .ok()
+                            .flatten()
+                    },
+                    move |thumbnail| {
+                        cosmic::Action::App(Message::AlbumsPage(AlbumsPageMessage::Thumbnail(
+                            key, thumbnail,
+                        )))
+                    },
+                ));
+            }
+        }
+        cosmic::Task::batch(tasks)
+    }
+    /// Sends the shown album's songs to the player
+    fn play_album(
+        &self,
+        message: impl FnOnce(Vec<Song>) -> PlayerMessage,
+    ) -> cosmic::Task<cosmic::Action<Message>> {
+        match &self.show_album {
+            Some(album) => cosmic::Task::done(cosmic::Action::App(Message::Player(message(
+                album.songs.clone(),
+            )))),
+            None => cosmic::Task::none(),
+        }
+    }
+    fn save_order(&self, config: &mut ConfigState) {
+        config.config.albums_sort = self.order.sort;
+        config.config.albums_sort_ascending = self.order.ascending;
+        config.config.albums_grouping = self.order.grouping;
+        config.save();
+    }
+    fn save_card_width(&self, config: &mut ConfigState) {
+        config.config.albums_card_width = self.card_width;
+        config.save();
+    }
+    /// Scrolls the grid to the first album whose sort key matches
+    fn jump<T: 'static>(&self, is_match: impl Fn(&str) -> bool) -> cosmic::Task<T> {
+        match self.grid.find(&self.order, is_match) {
+            Some(offset) => self.grid_scroll.scroll_to(offset),
+            None => cosmic::Task::none(),
+        }
+    }
+    /// Sort and grouping controls shown above the grid
+    fn order_controls(&self) -> Element<'_, Message> {
+        let space = cosmic::theme::spacing().space_s;
+        let sort_index = AlbumSort::ALL.iter().position(|s| *s == self.order.sort);
+        let grouping_index = AlbumGrouping::ALL
+            .iter()
+            .position(|g| *g == self.order.grouping);
+        let merge_controls: Element<'_, Message> = match &self.merge_selection {
+            Some(selection) => row::with_capacity(2)
+                .push(
+                    button::text(format!("Merge {} albums", selection.len()))
+                        .on_press_maybe(
+                            (selection.len() > 1)
+                                .then_some(Message::AlbumsPage(AlbumsPageMessage::MergeSelected)),
+                        )
+                        .class(cosmic::theme::Button::Suggested),
+                )
+                .push(
+                    button::text("Cancel")
+                        .on_press(Message::AlbumsPage(AlbumsPageMessage::ToggleMergeMode)),
+                )
+                .spacing(space)
+                .into(),
+            None => button::text("Merge albums")
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::ToggleMergeMode))
+                .into(),
+        };
+        let zoom = slider(MIN_CARD_WIDTH..=MAX_CARD_WIDTH, self.card_width, |width| {
+            Message::AlbumsPage(AlbumsPageMessage::CardWidth(width))
+        })
+        .on_release(Message::AlbumsPage(AlbumsPageMessage::SaveCardWidth))
+        .width(Length::Fixed(150.0));
+        row::with_capacity(7)
+            .push(text("Sort by"))
+            .push(dropdown(&AlbumSort::LABELS, sort_index, |i| {
+                Message::AlbumsPage(AlbumsPageMessage::SortBy(i))
+            }))
+            .push(
+                button::text(if self.order.ascending {
+                    "Ascending"
+                } else {
+                    "Descending"
+                })
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::ToggleSortDirection)),
+            )
+            .push(dropdown(&AlbumGrouping::LABELS, grouping_index, |i| {
+                Message::AlbumsPage(AlbumsPageMessage::GroupBy(i))
+            }))
+            .push(merge_controls)
+            .push(text("Size"))
+            .push(zoom)
+            .spacing(space)
+            .align_y(Alignment::Center)
+            .into()
     }
 }
 
@@ -56,109 +316,692 @@ impl Page for AlbumsPage {
     fn update(
         &mut self,
         message: Message,
+        library: &SongLibrary,
+        config: &mut ConfigState,
     ) -> (cosmic::Task<cosmic::Action<Message>>, Option<Box<dyn Page>>) {
+        let mut task = cosmic::Task::none();
+        if let Message::ModifiersChanged(modifiers) = message {
+            self.ctrl_held = modifiers.control();
+        }
+        if let Message::Zoom(lines) = message {
+            self.card_width =
+                (self.card_width + lines * CARD_ZOOM_STEP).clamp(MIN_CARD_WIDTH, MAX_CARD_WIDTH);
+            self.layout_grid();
+            // Every wheel notch zooms, wait until they stop to save
+            self.zooms += 1;
+            let zoom = self.zooms;
+            task = cosmic::Task::perform(tokio::time::sleep(ZOOM_SAVE_DELAY), move |_| {
+                cosmic::Action::App(Message::AlbumsPage(AlbumsPageMessage::ZoomStopped(zoom)))
+            });
+        }
+        if let Message::TypeToFind(c) = message
+            && self.show_album.is_none()
+        {
+            let prefix = self.type_to_find.push(c);
+            return (self.jump(|key| key.starts_with(&prefix)), None);
+        }
         if let Message::AlbumsPage(album_message) = message {
             match album_message {
-                AlbumsPageMessage::ShowAlbum(title) => {
-                    self.albums_library.show_album = Some(title);
+                AlbumsPageMessage::ShowAlbum(key) => {
+                    self.show_album = Some(AlbumDetail::new(key, library));
+                    self.album_scroll = ScrollState::default();
                 }
                 AlbumsPageMessage::BackToAllAlbums => {
-                    self.albums_library.show_album = None;
+                    self.show_album = None;
+                }
+                AlbumsPageMessage::PlayAlbum(index) => {
+                    return (
+                        self.play_album(|songs| PlayerMessage::PlaySongs(songs, index)),
+                        None,
+                    );
+                }
+                AlbumsPageMessage::ShuffleAlbum => {
+                    return (self.play_album(PlayerMessage::ShuffleSongs), None);
+                }
+                AlbumsPageMessage::QueueAlbum => {
+                    return (self.play_album(PlayerMessage::AddToQueue), None);
+                }
+                AlbumsPageMessage::PlayAlbumNext => {
+                    return (self.play_album(PlayerMessage::PlayNext), None);
+                }
+                AlbumsPageMessage::Filter(filter) => {
+                    self.filter = filter;
+                    self.rebuild_grid(library);
+                }
+                AlbumsPageMessage::SortBy(index) => {
+                    if let Some(sort) = AlbumSort::ALL.get(index) {
+                        self.order.sort = *sort;
+                        // Picking random again reshuffles
+                        if *sort == AlbumSort::Random {
+                            self.order.random_seed = fastrand::u64(..);
+                        }
+                        self.rebuild_grid(library);
+                        self.save_order(config);
+                    }
+                }
+                AlbumsPageMessage::ToggleSortDirection => {
+                    self.order.ascending = !self.order.ascending;
+                    self.rebuild_grid(library);
+                    self.save_order(config);
+                }
+                AlbumsPageMessage::GroupBy(index) => {
+                    if let Some(grouping) = AlbumGrouping::ALL.get(index) {
+                        self.order.grouping = *grouping;
+                        self.rebuild_grid(library);
+                        self.save_order(config);
+                    }
+                }
+                AlbumsPageMessage::ToggleMergeMode => {
+                    self.merge_selection = match self.merge_selection {
+                        Some(_) => None,
+                        None => Some(vec![]),
+                    };
+                }
+                AlbumsPageMessage::SelectForMerge(key) => {
+                    if let Some(selection) = self.merge_selection.as_mut() {
+                        match selection.iter().position(|selected| *selected == key) {
+                            Some(index) => {
+                                selection.remove(index);
+                            }
+                            None => selection.push(key),
+                        }
+                    }
                 }
-                AlbumsPageMessage::PopulateAlbumsLibrary => {}
-                AlbumsPageMessage::Populate(some_song) => {
-                    // eprintln!("Adding {:#?} to library", some_song);
-                    // if some_song.is_some() {
-                    //     self.albums_library.add_song(some_song.unwrap());
-                    // }
-                    some_song.map(|song| self.albums_library.add_song(song));
+                AlbumsPageMessage::JumpTo(letter) => {
+                    return (self.jump(|key| jump_letter(key) == letter), None);
+                }
+                AlbumsPageMessage::CardWidth(width) => {
+                    self.card_width = width;
+                    self.layout_grid();
+                }
+                AlbumsPageMessage::SaveCardWidth => {
+                    self.save_card_width(config);
+                }
+                AlbumsPageMessage::ZoomStopped(zoom) => {
+                    if zoom == self.zooms {
+                        self.save_card_width(config);
+                    }
+                }
+                AlbumsPageMessage::Thumbnail(key, thumbnail) => {
+                    // Unless the cards were zoomed to another size while it was made
+                    if let Some(slot) = self.thumbnails.get_mut(&key) {
+                        *slot = thumbnail;
+                    }
+                }
+                AlbumsPageMessage::Scrolled(viewport) => match self.show_album {
+                    Some(_) => self.album_scroll.update(viewport),
+                    None => {
+                        let width = self.grid_scroll.width;
+                        let offset = self.grid_scroll.offset;
+                        self.grid_scroll.update(viewport);
+                        // The scrollable moves before the wheel reaches Ctrl+scroll zooming, put it back
+                        if self.ctrl_held && self.grid_scroll.offset != offset {
+                            self.grid_scroll.offset = offset;
+                            task = self.grid_scroll.scroll_to(offset);
+                        }
+                        if self.grid_scroll.width != width {
+                            self.layout_grid();
+                        }
+                    }
+                },
+                AlbumsPageMessage::MergeSelected => {
+                    if let Some(selection) = self.merge_selection.take() {
+                        return (
+                            cosmic::Task::done(cosmic::Action::App(Message::MergeAlbums(
+                                selection,
+                            ))),
+                            None,
+                        );
+                    }
                 }
             }
         }
-        (cosmic::Task::none(), None)
+        (cosmic::Task::batch([task, self.request_thumbnails()]), None)
     }
-    fn view(&self) -> cosmic::Element<'_, Message> {
-        if self.albums_library.show_album.is_none() {
+    fn view<'a>(&'a self, _library: &'a SongLibrary) -> cosmic::Element<'a, Message> {
+        let Some(album) = self.show_album.as_ref() else {
             // eprintln!("Elements from albums");
-            return elements_from_albums(&self.albums_library);
-        }
+            let space = cosmic::theme::spacing().space_s;
+            let filter = filter_input(&self.filter, |f| {
+                Message::AlbumsPage(AlbumsPageMessage::Filter(f))
+            });
+            return column::with_capacity(3)
+                .push(container(filter).padding([space, space, 0, space]))
+                .push(container(self.order_controls()).padding([0, space]))
+                .push(elements_from_albums(
+                    &self.grid,
+                    &self.thumbnails,
+                    self.merge_selection.as_deref(),
+                    &self.grid_scroll,
+                    self.card_width,
+                ))
+                .into();
+        };
         // eprintln!("Elements from songs");
-        elements_from_songs(
-            self.albums_library.show_album.as_ref().unwrap(),
-            &self.albums_library,
+        elements_from_songs(album, &self.album_scroll)
+    }
+    fn library_changed(&mut self, library: &SongLibrary) -> cosmic::Task<cosmic::Action<Message>> {
+        if let Some(album) = self.show_album.take() {
+            self.show_album = Some(AlbumDetail::new(album.key, library));
+        }
+        self.rebuild_grid(library);
+        self.request_thumbnails()
+    }
+}
+
+/// Cover size in the album header
+const ALBUM_COVER_SIZE: f32 = 200.0;
+
+/// Height of a track in the album's track list, including the gap below it
+const TRACK_ROW_HEIGHT: f32 = 56.0;
+
+/// The album page's songs in playing order, with the details its header shows
+/// Made when the album is opened and again when the library changes, rather than on every view
+struct AlbumDetail {
+    key: String,
+    songs: Vec<Song>,
+    title: String,
+    artist: Option<String>,
+    /// Genre, year, track count and runtime
+    details: String,
+    has_merges: bool,
+}
+impl AlbumDetail {
+    fn new(key: String, library: &SongLibrary) -> Self {
+        let mut songs = library.get_album(&key);
+        songs.sort();
+        let artist = most_common(
+            songs
+                .iter()
+                .filter_map(|song| song.album_artist.as_deref().or(song.artist.as_deref()))
+                .map(str::trim),
         )
+        .map(str::to_string);
+        let genre = most_common(
+            songs
+                .iter()
+                .filter_map(|song| song.genre.as_deref())
+                .map(str::trim),
+        );
+        let year = songs.iter().filter_map(|song| song.year).min();
+        let runtime: Duration = songs.iter().map(|song| song.duration).sum();
+        let mut details = vec![format!(
+            "{} {}",
+            songs.len(),
+            if songs.len() == 1 { "track" } else { "tracks" }
+        )];
+        details.push(runtime.string_mins_secs());
+        if let Some(year) = year {
+            details.insert(0, year.to_string());
+        }
+        if let Some(genre) = genre {
+            details.insert(0, genre.to_string());
+        }
+        AlbumDetail {
+            has_merges: library.has_merged_albums(&key),
+            title: album_title(&songs),
+            details: details.join(" · "),
+            key,
+            songs,
+            artist,
+        }
     }
 }
 
-fn elements_from_songs<'a>(album_title: &str, library: &'a SongLibrary) -> Element<'a, Message> {
+fn elements_from_songs<'a>(album: &'a AlbumDetail, scroll: &ScrollState) -> Element<'a, Message> {
     // println!("Displaying...");
     let space = cosmic::theme::spacing().space_s;
-    let mut songs_list: Vec<Element<Message>> = vec![];
-    songs_list.push(
-        button::text("Back")
-            .on_press(Message::AlbumsPage(AlbumsPageMessage::BackToAllAlbums))
-            .into(),
-    );
-    let album: Vec<Song> = library.get_album(album_title);
-
-    album.clone().iter().enumerate().for_each(|(i, song)| {
-        let button = button::custom(song.display())
-            .on_press(Message::Player(PlayerMessage::PlaySongs(album.clone(), i)));
-        songs_list.push(button.into());
-    });
-    // println!("Done");
-    scrollable(
-        column::with_children(songs_list)
+    let Some(first_song) = album.songs.first() else {
+        return column::with_capacity(2)
+            .push(
+                button::text("Back")
+                    .on_press(Message::AlbumsPage(AlbumsPageMessage::BackToAllAlbums)),
+            )
+            .push(text("This album has no songs."))
             .spacing(space)
-            .padding(space),
-    )
-    .into()
+            .padding(space)
+            .into();
+    };
+
+    let mut navigation = row::with_capacity(2).push(
+        button::text("Back").on_press(Message::AlbumsPage(AlbumsPageMessage::BackToAllAlbums)),
+    );
+    if album.has_merges {
+        navigation = navigation.push(
+            button::text("Unmerge albums").on_press(Message::UnmergeAlbum(album.key.clone())),
+        );
+    }
+
+    let actions = row::with_capacity(4)
+        .push(
+            button::text("Play")
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::PlayAlbum(0)))
+                .class(cosmic::theme::Button::Suggested),
+        )
+        .push(
+            button::text("Shuffle").on_press(Message::AlbumsPage(AlbumsPageMessage::ShuffleAlbum)),
+        )
+        .push(
+            button::text("Add to queue")
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::QueueAlbum)),
+        )
+        .push(
+            button::text("Play next")
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::PlayAlbumNext)),
+        )
+        .spacing(space);
+    let info = column::with_capacity(4)
+        .push(text::title3(album.title.as_str()))
+        .push(text(album.artist.as_deref().unwrap_or("Unknown Artist")))
+        .push(text::caption(album.details.as_str()))
+        .push(actions)
+        .spacing(space);
+    let header = row::with_capacity(2)
+        .push(
+            image(first_song.picture.clone())
+                .width(ALBUM_COVER_SIZE)
+                .height(ALBUM_COVER_SIZE),
+        )
+        .push(info)
+        .spacing(space)
+        .align_y(Alignment::End);
+
+    // The navigation and header scroll with the tracks, as the first two rows of the list
+    let navigation = navigation.spacing(space);
+    let mut top_rows: [Option<Element<'a, Message>>; 2] =
+        [Some(navigation.into()), Some(header.into())];
+    let top_heights = [40.0, ALBUM_COVER_SIZE + f32::from(space)];
+    let heights: Vec<f32> = top_heights
+        .into_iter()
+        .chain(std::iter::repeat_n(TRACK_ROW_HEIGHT, album.songs.len()))
+        .collect();
+    let album_artist_key = album.artist.as_deref().map(group_key);
+    let track_list = virtual_list::list_with_heights(
+        &heights,
+        scroll,
+        |row_index| {
+            if let Some(top_row) = top_rows.get_mut(row_index) {
+                return top_row
+                    .take()
+                    .unwrap_or_else(|| column::with_capacity(0).into());
+            }
+            let i = row_index - top_heights.len();
+            let song = &album.songs[i];
+            // Only name the artist on tracks that aren't by the album artist, e.g. features on a compilation
+            let artist = song
+                .artist
+                .as_deref()
+                .filter(|artist| Some(group_key(artist)) != album_artist_key);
+            let mut title = column::with_capacity(2).push(text(song.title.as_str()));
+            if let Some(artist) = artist {
+                title = title.push(text::caption(artist));
+            }
+            let track_row = row::with_capacity(3)
+                .push(
+                    text(song.index.map(|i| i.to_string()).unwrap_or_default())
+                        .width(Length::Fixed(30.0)),
+                )
+                .push(title.width(Length::Fill))
+                .push(text(song.duration.string_mins_secs()))
+                .spacing(space)
+                .align_y(Alignment::Center);
+            button::custom(track_row)
+                .on_press(Message::AlbumsPage(AlbumsPageMessage::PlayAlbum(i)))
+                .width(Length::Fill)
+                .into()
+        },
+        |viewport| Message::AlbumsPage(AlbumsPageMessage::Scrolled(viewport)),
+    );
+    container(track_list).padding(space).into()
 }
 
-fn elements_from_albums(library: &SongLibrary) -> Element<'static, Message> {
-    // eprintln!("{:#?}", library);
+/// Album totals used for sorting and grouping
+struct AlbumSummary {
+    key: String,
+    title: String,
+    artist: Option<String>,
+    /// The first song's cover
+    picture: image::Handle,
+    /// Keys from the first song, see SongSortKeys
+    title_key: String,
+    artist_key: String,
+    year: Option<u32>,
+    date_added: SystemTime,
+    last_played: Option<SystemTime>,
+    play_count: u32,
+}
+impl AlbumSummary {
+    fn new(album: &Album, library: &SongLibrary) -> Self {
+        let songs = &album.songs;
+        let stats: Vec<SongStats> = songs.iter().map(|song| library.stats(song)).collect();
+        AlbumSummary {
+            key: album.key.clone(),
+            title: album.title.clone(),
+            artist: most_common(
+                songs
+                    .iter()
+                    .filter_map(|song| song.album_artist.as_deref().or(song.artist.as_deref()))
+                    .map(str::trim),
+            )
+            .map(String::from),
+            picture: songs[0].picture.clone(),
+            title_key: songs[0].sort_keys.album.clone(),
+            artist_key: songs[0].sort_keys.album_artist.clone(),
+            year: songs.iter().filter_map(|song| song.year).min(),
+            // An album counts as added when its newest song was
+            date_added: songs
+                .iter()
+                .map(|song| song.date_added)
+                .max()
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            last_played: stats.iter().filter_map(|stats| stats.last_played).max(),
+            play_count: stats.iter().map(|stats| stats.play_count).sum(),
+        }
+    }
+    fn compare(&self, other: &Self, order: &AlbumOrder) -> Ordering {
+        match order.sort {
+            AlbumSort::Title => self.title_key.cmp(&other.title_key),
+            AlbumSort::Artist => self.artist_key.cmp(&other.artist_key),
+            AlbumSort::Year => self.year.cmp(&other.year),
+            AlbumSort::DateAdded => self.date_added.cmp(&other.date_added),
+            AlbumSort::LastPlayed => self.last_played.cmp(&other.last_played),
+            AlbumSort::PlayCount => self.play_count.cmp(&other.play_count),
+            AlbumSort::Random => order
+                .random_key(&self.key)
+                .cmp(&order.random_key(&other.key)),
+        }
+        // Keep albums that compare equal in a predictable order
+        .then_with(|| self.title_key.cmp(&other.title_key))
+    }
+    /// The key jumping to a letter or typing to find looks at, the one the grid is sorted by
+    fn jump_key(&self, order: &AlbumOrder) -> &str {
+        match (order.sort, order.grouping) {
+            (AlbumSort::Artist, _) | (_, AlbumGrouping::Artist) => &self.artist_key,
+            _ => &self.title_key,
+        }
+    }
+    /// The header of the group this album belongs in
+    fn group(&self, grouping: AlbumGrouping) -> Option<String> {
+        match grouping {
+            AlbumGrouping::None => None,
+            AlbumGrouping::FirstLetter => Some(
+                self.title_key
+                    .chars()
+                    .next()
+                    .filter(|c| c.is_alphabetic())
+                    .map(|c| c.to_uppercase().to_string())
+                    .unwrap_or_else(|| "#".to_string()),
+            ),
+            AlbumGrouping::Artist => Some(
+                self.artist
+                    .clone()
+                    .unwrap_or_else(|| "Unknown Artist".to_string()),
+            ),
+            AlbumGrouping::Year => Some(
+                self.year
+                    .map(|year| year.to_string())
+                    .unwrap_or_else(|| "Unknown Year".to_string()),
+            ),
+        }
+    }
+}
+
+/// The albums shown in the grid in display order, and the rows they are laid out in
+/// Kept in the page and only made again when something it's made from changes, not every time it's drawn
+struct AlbumGrid {
+    albums: Vec<AlbumSummary>,
+    rows: Vec<GridRow>,
+    heights: Vec<f32>,
+    card: CardLayout,
+}
+impl AlbumGrid {
+    /// Summarises the library's albums that match the filter, in order
+    /// The grid still has to be laid out
+    fn new(library: &SongLibrary, filter: &str, order: &AlbumOrder) -> Self {
+        let filter = parse_filter(filter);
+        let mut albums: Vec<AlbumSummary> = library
+            .get_albums()
+            .iter()
+            // Skip albums without any songs matching the filter
+            .filter(|album| {
+                filter.as_ref().is_none_or(|filter| {
+                    album
+                        .songs
+                        .iter()
+                        .any(|song| filter.matches(song, &library.stats(song)))
+                })
+            })
+            .map(|album| AlbumSummary::new(album, library))
+            .collect();
+        albums.sort_by(|a, b| {
+            let ordering = a.compare(b, order);
+            if order.ascending {
+                ordering
+            } else {
+                ordering.reverse()
+            }
+        });
+        // Groups follow the sort order of their first album, except where the group is what's sorted on
+        if order.grouping != AlbumGrouping::None {
+            let group_key = |album: &AlbumSummary| album.group(order.grouping);
+            albums.sort_by(|a, b| {
+                let ordering = match order.grouping {
+                    AlbumGrouping::Year => a.year.cmp(&b.year),
+                    AlbumGrouping::Artist => a.artist_key.cmp(&b.artist_key),
+                    _ => group_key(a).cmp(&group_key(b)),
+                };
+                if order.ascending {
+                    ordering
+                } else {
+                    ordering.reverse()
+                }
+            });
+        }
+        AlbumGrid {
+            albums,
+            rows: vec![],
+            heights: vec![],
+            card: CardLayout::new(DEFAULT_CARD_WIDTH, 0.0, 0.0),
+        }
+    }
+    /// Lays the albums out in rows of cards for the width of the grid, with a header row before each group
+    fn layout(&mut self, grouping: AlbumGrouping, width: f32, card_width: f32) {
+        let space = cosmic::theme::spacing().space_s;
+        let space_s = cosmic::theme::spacing().space_xxs;
+        let space_xs = cosmic::theme::spacing().space_xxxs;
+        self.card = CardLayout::new(card_width, f32::from(space_s), f32::from(space_xs));
+        let columns = virtual_list::grid_columns(
+            width - 2.0 * f32::from(space) - JUMP_STRIP_WIDTH,
+            card_width,
+            f32::from(space),
+        );
+        self.rows.clear();
+        let mut last_group = None;
+        for (i, album) in self.albums.iter().enumerate() {
+            let group = album.group(grouping);
+            if i == 0 || group != last_group {
+                if let Some(group) = &group {
+                    self.rows.push(GridRow::Header(group.clone()));
+                }
+                self.rows.push(GridRow::Cards(i..i + 1));
+            } else if let Some(GridRow::Cards(cards)) = self.rows.last_mut()
+                && cards.len() < columns
+            {
+                cards.end = i + 1;
+            } else {
+                self.rows.push(GridRow::Cards(i..i + 1));
+            }
+            last_group = group;
+        }
+        self.heights = self
+            .rows
+            .iter()
+            .map(|row| match row {
+                GridRow::Header(_) => GROUP_HEADER_HEIGHT,
+                GridRow::Cards(_) => self.card.height + f32::from(space),
+            })
+            .collect();
+    }
+    /// Scroll offset of the first album whose sort key matches, including its group's header
+    fn find(&self, order: &AlbumOrder, is_match: impl Fn(&str) -> bool) -> Option<f32> {
+        let album = self
+            .albums
+            .iter()
+            .position(|album| is_match(album.jump_key(order)))?;
+        let mut row = self
+            .rows
+            .iter()
+            .position(|row| matches!(row, GridRow::Cards(cards) if cards.contains(&album)))?;
+        if row > 0 && matches!(self.rows[row - 1], GridRow::Header(_)) {
+            row -= 1;
+        }
+        Some(self.heights[..row].iter().sum())
+    }
+}
+
+fn elements_from_albums<'a>(
+    grid: &'a AlbumGrid,
+    thumbnails: &HashMap<ThumbnailKey, Option<image::Handle>>,
+    merge_selection: Option<&[String]>,
+    scroll: &ScrollState,
+    card_width: f32,
+) -> Element<'a, Message> {
     let space = cosmic::theme::spacing().space_s;
     let space_s = cosmic::theme::spacing().space_xxs;
     let space_xs = cosmic::theme::spacing().space_xxxs;
-    let mut albums_grid: Vec<Element<Message>> = vec![];
-    // eprintln!("{:#?}", library);
-    for album in library.get_albums() {
-        static CARD_WIDTH: f32 = 100.0;
-        let picture: Element<Message> = image(album.1[0].picture.clone())
+    let layout = &grid.card;
+    let thumbnail_size = thumbnail::size_for(layout.picture_size);
+
+    let album_card = |album: &AlbumSummary| -> Element<'a, Message> {
+        // Until its thumbnail is made, the full cover is drawn
+        let cover = thumbnails
+            .get(&ThumbnailKey::new(&album.picture, thumbnail_size))
+            .and_then(Option::as_ref)
+            .unwrap_or(&album.picture);
+        let picture: Element<Message> = image(cover.clone())
+            .width(layout.picture_size)
+            .height(layout.picture_size)
+            .content_fit(ContentFit::Cover)
             .border_radius([4.0; 4]) // Currently doesn't work with hardware rendering
             .into();
-        let label = text(album.0.clone())
-            .center()
-            .wrapping(Wrapping::WordOrGlyph);
-        let album_card: Element<Message> = container(
+        let title = truncate(&album.title, layout.title_lines * layout.chars_per_line);
+        let mut label =
+            column::with_capacity(2).push(text(title).width(Length::Fill).center().wrapping(
+                if layout.title_lines > 1 {
+                    Wrapping::WordOrGlyph
+                } else {
+                    Wrapping::None
+                },
+            ));
+        if layout.show_artist {
+            label = label.push(
+                text::caption(truncate(
+                    album.artist.as_deref().unwrap_or("Unknown Artist"),
+                    layout.chars_per_line,
+                ))
+                .width(Length::Fill)
+                .center()
+                .wrapping(Wrapping::None),
+            );
+        }
+        // In merge mode, clicking a card selects it instead of opening it
+        let (on_press, selected) = match merge_selection {
+            Some(selection) => (
+                AlbumsPageMessage::SelectForMerge(album.key.clone()),
+                selection.iter().any(|selected| *selected == album.key),
+            ),
+            None => (AlbumsPageMessage::ShowAlbum(album.key.clone()), false),
+        };
+        container(
             button::custom(
                 column::with_capacity(2)
                     .push(picture)
                     .push(label)
                     .spacing(space_xs),
             )
-            .on_press(Message::AlbumsPage(AlbumsPageMessage::ShowAlbum(
-                album.0.clone(),
-            )))
-            .height(CARD_WIDTH * 1.5)
+            .on_press(Message::AlbumsPage(on_press))
+            .class(if selected {
+                cosmic::theme::Button::Suggested
+            } else {
+                cosmic::theme::Button::Standard
+            })
+            .height(layout.height)
             .padding(space_s),
         )
         .style(card_style)
-        .max_width(CARD_WIDTH)
-        .into();
-        albums_grid.push(album_card);
-    }
-
-    Element::from(
-        container(scrollable(
-            container(
-                flex_row(albums_grid)
-                    .justify_content(JustifyContent::SpaceEvenly)
-                    .spacing(space)
-                    // .padding(space)
-                    .width(Length::Fill),
-            )
-            .padding(space),
-        )), // .padding(space),
-    )
+        .width(card_width)
+        .clip(true)
+        .into()
+    };
+
+    let list = virtual_list::list_with_heights(
+        &grid.heights,
+        scroll,
+        |i| match &grid.rows[i] {
+            GridRow::Header(group) => text::title3(group.clone()).into(),
+            GridRow::Cards(cards) => {
+                let mut cards_row = row::with_capacity(cards.len()).spacing(space);
+                for album in &grid.albums[cards.clone()] {
+                    cards_row = cards_row.push(album_card(album));
+                }
+                container(cards_row).center_x(Length::Fill).into()
+            }
+        },
+        |viewport| Message::AlbumsPage(AlbumsPageMessage::Scrolled(viewport)),
+    );
+    row::with_capacity(2)
+        .push(container(list).padding([0, space]).width(Length::Fill))
+        .push(virtual_list::jump_strip(JUMP_STRIP_WIDTH, |letter| {
+            Message::AlbumsPage(AlbumsPageMessage::JumpTo(letter))
+        }))
+        .into()
+}
+
+/// Sizes of the parts of an album card for a card width
+struct CardLayout {
+    picture_size: f32,
+    /// Lines the title may wrap over before it is cut short
+    title_lines: usize,
+    /// Only larger cards have room to name the artist
+    show_artist: bool,
+    chars_per_line: usize,
+    height: f32,
+}
+impl CardLayout {
+    fn new(width: f32, padding: f32, spacing: f32) -> Self {
+        let picture_size = width - 2.0 * padding;
+        let (title_lines, show_artist) = if width < 120.0 {
+            (1, false)
+        } else if width < 200.0 {
+            (2, false)
+        } else {
+            (2, true)
+        };
+        let label_lines = title_lines + usize::from(show_artist);
+        CardLayout {
+            picture_size,
+            title_lines,
+            show_artist,
+            chars_per_line: ((picture_size / LABEL_CHAR_WIDTH) as usize).max(1),
+            height: picture_size + 2.0 * padding + spacing + label_lines as f32 * LABEL_LINE_HEIGHT,
+        }
+    }
+}
+
+/// Cuts text down to the given number of characters, ending it with an ellipsis if it was too long
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// A row of the virtualized album grid
+enum GridRow {
+    Header(String),
+    /// Indices of the albums shown in the row
+    Cards(Range<usize>),
 }
@@ -1,5 +1,7 @@
 use crate::app::Message;
+use crate::config::ConfigState;
 use crate::page::Page;
+use crate::song_library::SongLibrary;
 
 #[derive(Debug, Clone)]
 pub enum ArtistsPageMessage {
@@ -9,12 +11,14 @@ pub enum ArtistsPageMessage {
 pub struct ArtistsPage {}
 
 impl Page for ArtistsPage {
-    fn view(&self) -> cosmic::Element<'_, Message> {
+    fn view<'a>(&'a self, library: &'a SongLibrary) -> cosmic::Element<'a, Message> {
         todo!();
     }
     fn update(
         &mut self,
         message: Message,
+        library: &SongLibrary,
+        config: &mut ConfigState,
     ) -> (cosmic::Task<cosmic::Action<Message>>, Option<Box<dyn Page>>) {
         todo!();
     }
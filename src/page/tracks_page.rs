@@ -0,0 +1,458 @@
+use crate::app::Message;
+use crate::config::ConfigState;
+use crate::library_db::SongStats;
+use crate::page::{Page, filter_input, parse_filter};
+use crate::player::{DurationDisplayExt, PlayerMessage};
+use crate::song::Song;
+use crate::song_library::SongLibrary;
+use crate::sort_key::parse_articles;
+use crate::virtual_list::{self, ScrollState, TypeToFind, jump_letter};
+use cosmic::Element;
+use cosmic::iced::widget::scrollable::Viewport;
+use cosmic::iced::{Alignment, Length};
+use cosmic::iced_core::text::Wrapping;
+use cosmic::widget::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Height of a single row in the table, rows must all be the same height for virtualization
+const ROW_HEIGHT: f32 = 32.0;
+/// Width of the A-Z strip next to the table
+const JUMP_STRIP_WIDTH: f32 = 24.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TrackColumn {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    Track,
+    Duration,
+    DateAdded,
+    PlayCount,
+    Rating,
+}
+impl TrackColumn {
+    pub const ALL: [TrackColumn; 10] = [
+        TrackColumn::Title,
+        TrackColumn::Artist,
+        TrackColumn::Album,
+        TrackColumn::Genre,
+        TrackColumn::Year,
+        TrackColumn::Track,
+        TrackColumn::Duration,
+        TrackColumn::DateAdded,
+        TrackColumn::PlayCount,
+        TrackColumn::Rating,
+    ];
+    /// Columns shown before the user changes anything
+    pub const DEFAULT: [TrackColumn; 6] = [
+        TrackColumn::Title,
+        TrackColumn::Artist,
+        TrackColumn::Album,
+        TrackColumn::Year,
+        TrackColumn::Duration,
+        TrackColumn::Rating,
+    ];
+    pub fn label(&self) -> &'static str {
+        match self {
+            TrackColumn::Title => "Title",
+            TrackColumn::Artist => "Artist",
+            TrackColumn::Album => "Album",
+            TrackColumn::Genre => "Genre",
+            TrackColumn::Year => "Year",
+            TrackColumn::Track => "Track",
+            TrackColumn::Duration => "Duration",
+            TrackColumn::DateAdded => "Date Added",
+            TrackColumn::PlayCount => "Plays",
+            TrackColumn::Rating => "Rating",
+        }
+    }
+    /// Text columns share the leftover space, numeric columns are fixed
+    fn width(&self) -> Length {
+        match self {
+            TrackColumn::Title => Length::FillPortion(3),
+            TrackColumn::Artist | TrackColumn::Album => Length::FillPortion(2),
+            TrackColumn::Genre => Length::FillPortion(1),
+            TrackColumn::Year | TrackColumn::Track | TrackColumn::PlayCount => Length::Fixed(60.0),
+            TrackColumn::Duration => Length::Fixed(80.0),
+            TrackColumn::DateAdded => Length::Fixed(110.0),
+            TrackColumn::Rating => Length::Fixed(130.0),
+        }
+    }
+    fn compare(&self, a: (&Song, &SongStats), b: (&Song, &SongStats)) -> Ordering {
+        let ((song_a, stats_a), (song_b, stats_b)) = (a, b);
+        match self {
+            TrackColumn::Title => song_a.sort_keys.title.cmp(&song_b.sort_keys.title),
+            TrackColumn::Artist => song_a.sort_keys.artist.cmp(&song_b.sort_keys.artist),
+            TrackColumn::Album => song_a.sort_keys.album.cmp(&song_b.sort_keys.album),
+            TrackColumn::Genre => song_a.sort_keys.genre.cmp(&song_b.sort_keys.genre),
+            TrackColumn::Year => song_a.year.cmp(&song_b.year),
+            TrackColumn::Track => song_a.index.cmp(&song_b.index),
+            TrackColumn::Duration => song_a.duration.cmp(&song_b.duration),
+            TrackColumn::DateAdded => song_a.date_added.cmp(&song_b.date_added),
+            TrackColumn::PlayCount => stats_a.play_count.cmp(&stats_b.play_count),
+            TrackColumn::Rating => stats_a.rating.cmp(&stats_b.rating),
+        }
+    }
+    /// What jumping and typing to find match against, the sort key for text columns
+    fn jump_key(&self, song: &Song, stats: &SongStats) -> String {
+        match self {
+            TrackColumn::Title => song.sort_keys.title.clone(),
+            TrackColumn::Artist => song.sort_keys.artist.clone(),
+            TrackColumn::Album => song.sort_keys.album.clone(),
+            TrackColumn::Genre => song.sort_keys.genre.clone(),
+            _ => cell_text(self, song, stats),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TracksPageMessage {
+    Play(PathBuf),       // Plays the whole table starting from the song at the path
+    SortBy(TrackColumn), // Sorts by the column, or flips the direction if already sorted by it
+    ToggleColumn(TrackColumn, bool), // Shows or hides a column
+    MoveColumn(TrackColumn, isize), // Moves a visible column left (negative) or right (positive)
+    ShowColumnEditor(bool),
+    Scrolled(Viewport),
+    JumpTo(char), // Scrolls to the first song starting with the letter, '#' for anything else
+    Filter(String), // Only shows songs matching the filter query
+    EditArticles(String), // Edits the list of articles ignored when sorting, until it's applied
+}
+
+/// Every song in the library as a sortable table
+pub struct TracksPage {
+    columns: Vec<TrackColumn>,
+    sort: TrackColumn,
+    sort_ascending: bool,
+    show_column_editor: bool,
+    /// The articles ignored when sorting as they are being edited, comma separated
+    articles: String,
+    filter: String,
+    /// Indices into the library's songs that match the filter, in the table's order
+    rows: Vec<usize>,
+    scroll: ScrollState,
+    type_to_find: TypeToFind,
+}
+
+impl TracksPage {
+    pub fn new(config: &ConfigState, library: &SongLibrary) -> TracksPage {
+        let mut page = TracksPage {
+            columns: config.config.tracks_columns.clone(),
+            sort: config.config.tracks_sort,
+            sort_ascending: config.config.tracks_sort_ascending,
+            show_column_editor: false,
+            articles: config.config.sort_articles.join(", "),
+            filter: String::new(),
+            rows: vec![],
+            scroll: ScrollState::default(),
+            type_to_find: TypeToFind::default(),
+        };
+        page.sort_rows(library);
+        page
+    }
+    /// Scrolls the table to the first song whose key in the sorted column matches
+    fn jump<T: 'static>(
+        &self,
+        library: &SongLibrary,
+        is_match: impl Fn(&str) -> bool,
+    ) -> cosmic::Task<T> {
+        let row = self.rows.iter().position(|i| {
+            let song = &library.songs()[*i];
+            is_match(&self.sort.jump_key(song, &library.stats(song)))
+        });
+        match row {
+            Some(row) => self.scroll.scroll_to(row as f32 * ROW_HEIGHT),
+            None => cosmic::Task::none(),
+        }
+    }
+    /// Finds the library's songs that match the filter and puts them in the table's order
+    /// Only done when the library, filter or sort changes, not every time the table is drawn
+    fn sort_rows(&mut self, library: &SongLibrary) {
+        let filter = parse_filter(&self.filter);
+        let mut songs: Vec<(usize, SongStats)> = library
+            .songs()
+            .iter()
+            .enumerate()
+            .map(|(i, song)| (i, library.stats(song)))
+            .filter(|(i, stats)| {
+                filter
+                    .as_ref()
+                    .is_none_or(|f| f.matches(&library.songs()[*i], stats))
+            })
+            .collect();
+        songs.sort_by(|(a, stats_a), (b, stats_b)| {
+            let (song_a, song_b) = (&library.songs()[*a], &library.songs()[*b]);
+            let ordering = self.sort.compare((song_a, stats_a), (song_b, stats_b));
+            if self.sort_ascending {
+                ordering
+            } else {
+                ordering.reverse()
+            }
+        });
+        self.rows = songs.into_iter().map(|(i, _)| i).collect();
+    }
+    fn save_layout(&self, config: &mut ConfigState) {
+        config.config.tracks_columns = self.columns.clone();
+        config.config.tracks_sort = self.sort;
+        config.config.tracks_sort_ascending = self.sort_ascending;
+        config.save();
+    }
+    fn header(&self) -> Element<'_, Message> {
+        let mut header = row::with_capacity(self.columns.len()).align_y(Alignment::Center);
+        for column in self.columns.iter() {
+            let arrow = match (self.sort == *column, self.sort_ascending) {
+                (false, _) => "",
+                (true, true) => " ▲",
+                (true, false) => " ▼",
+            };
+            header = header.push(
+                button::text(column.label().to_string() + arrow)
+                    .on_press(Message::TracksPage(TracksPageMessage::SortBy(*column)))
+                    .width(column.width()),
+            );
+        }
+        header.into()
+    }
+    fn column_editor(&self) -> Element<'_, Message> {
+        let space = cosmic::theme::spacing().space_s;
+        let mut editor = column::with_capacity(TrackColumn::ALL.len() + 1).spacing(space);
+        for column in TrackColumn::ALL {
+            let visible = self.columns.contains(&column);
+            let mut entry = row::with_capacity(3)
+                .push(toggler(visible).label(column.label()).on_toggle(move |v| {
+                    Message::TracksPage(TracksPageMessage::ToggleColumn(column, v))
+                }))
+                .spacing(space)
+                .align_y(Alignment::Center);
+            if visible {
+                entry = entry
+                    .push(button::text("◀").on_press(Message::TracksPage(
+                        TracksPageMessage::MoveColumn(column, -1),
+                    )))
+                    .push(button::text("▶").on_press(Message::TracksPage(
+                        TracksPageMessage::MoveColumn(column, 1),
+                    )));
+            }
+            editor = editor.push(entry);
+        }
+        // Names starting with these words sort by the word after them, "The Beatles" under B
+        let articles = row::with_capacity(3)
+            .push(text("Ignore when sorting"))
+            .push(
+                text_input("The, A, An", &self.articles)
+                    .on_input(|articles| {
+                        Message::TracksPage(TracksPageMessage::EditArticles(articles))
+                    })
+                    .width(Length::Fixed(200.0)),
+            )
+            .push(
+                button::text("Apply")
+                    .on_press(Message::SetSortArticles(parse_articles(&self.articles))),
+            )
+            .spacing(space)
+            .align_y(Alignment::Center);
+        container(editor.push(articles)).padding(space).into()
+    }
+}
+
+impl Page for TracksPage {
+    fn update(
+        &mut self,
+        message: Message,
+        library: &SongLibrary,
+        config: &mut ConfigState,
+    ) -> (cosmic::Task<cosmic::Action<Message>>, Option<Box<dyn Page>>) {
+        if let Message::TypeToFind(c) = message {
+            let prefix = self.type_to_find.push(c);
+            return (self.jump(library, |key| key.starts_with(&prefix)), None);
+        }
+        let Message::TracksPage(tracks_message) = message else {
+            return (cosmic::Task::none(), None);
+        };
+        match tracks_message {
+            TracksPageMessage::Play(path) => {
+                let songs: Vec<Song> = self
+                    .rows
+                    .iter()
+                    .map(|i| library.songs()[*i].clone())
+                    .collect();
+                // The song is looked up by path, in case the table changed since it was clicked
+                let Some(index) = songs.iter().position(|song| song.path == path) else {
+                    return (cosmic::Task::none(), None);
+                };
+                return (
+                    cosmic::Task::done(cosmic::Action::App(Message::Player(
+                        PlayerMessage::PlaySongs(songs, index),
+                    ))),
+                    None,
+                );
+            }
+            TracksPageMessage::SortBy(column) => {
+                if self.sort == column {
+                    self.sort_ascending = !self.sort_ascending;
+                } else {
+                    self.sort = column;
+                    self.sort_ascending = true;
+                }
+                self.sort_rows(library);
+                self.save_layout(config);
+            }
+            TracksPageMessage::ToggleColumn(column, visible) => {
+                if visible && !self.columns.contains(&column) {
+                    self.columns.push(column);
+                } else if !visible {
+                    self.columns.retain(|c| *c != column);
+                }
+                self.save_layout(config);
+            }
+            TracksPageMessage::MoveColumn(column, offset) => {
+                if let Some(index) = self.columns.iter().position(|c| *c == column) {
+                    let new_index = (index as isize + offset)
+                        .clamp(0, self.columns.len() as isize - 1)
+                        as usize;
+                    self.columns.swap(index, new_index);
+                    self.save_layout(config);
+                }
+            }
+            TracksPageMessage::Filter(filter) => {
+                self.filter = filter;
+                self.sort_rows(library);
+            }
+            TracksPageMessage::EditArticles(articles) => {
+                self.articles = articles;
+            }
+            TracksPageMessage::ShowColumnEditor(show) => {
+                self.show_column_editor = show;
+            }
+            TracksPageMessage::Scrolled(viewport) => {
+                self.scroll.update(viewport);
+            }
+            TracksPageMessage::JumpTo(letter) => {
+                return (self.jump(library, |key| jump_letter(key) == letter), None);
+            }
+        }
+        (cosmic::Task::none(), None)
+    }
+    fn view<'a>(&'a self, library: &'a SongLibrary) -> Element<'a, Message> {
+        let space = cosmic::theme::spacing().space_s;
+
+        let table = virtual_list::list(
+            self.rows.len(),
+            ROW_HEIGHT,
+            &self.scroll,
+            |row| {
+                let song = &library.songs()[self.rows[row]];
+                track_row(&self.columns, song, &library.stats(song))
+            },
+            |viewport| Message::TracksPage(TracksPageMessage::Scrolled(viewport)),
+        );
+
+        let columns_button = button::text("Columns").on_press(Message::TracksPage(
+            TracksPageMessage::ShowColumnEditor(!self.show_column_editor),
+        ));
+        let toolbar = row::with_capacity(2)
+            .push(
+                container(filter_input(&self.filter, |f| {
+                    Message::TracksPage(TracksPageMessage::Filter(f))
+                }))
+                .width(Length::Fill),
+            )
+            .push(columns_button)
+            .spacing(space);
+        let mut page = column::with_capacity(4)
+            .push(toolbar)
+            .spacing(space)
+            .padding(space);
+        if self.show_column_editor {
+            page = page.push(self.column_editor());
+        }
+        let table = row::with_capacity(2)
+            .push(table)
+            .push(virtual_list::jump_strip(JUMP_STRIP_WIDTH, |letter| {
+                Message::TracksPage(TracksPageMessage::JumpTo(letter))
+            }));
+        page.push(self.header()).push(table).into()
+    }
+    fn library_changed(&mut self, library: &SongLibrary) -> cosmic::Task<cosmic::Action<Message>> {
+        self.sort_rows(library);
+        cosmic::Task::none()
+    }
+}
+
+fn track_row<'a>(columns: &[TrackColumn], song: &Song, stats: &SongStats) -> Element<'a, Message> {
+    let mut cells = row::with_capacity(columns.len()).align_y(Alignment::Center);
+    for column in columns {
+        let cell: Element<Message> = match column {
+            TrackColumn::Rating => rating_stars(song, stats.rating),
+            _ => text(cell_text(column, song, stats))
+                .wrapping(Wrapping::None)
+                .into(),
+        };
+        cells = cells.push(container(cell).width(column.width()).clip(true));
+    }
+    button::custom(cells)
+        .on_press(Message::TracksPage(TracksPageMessage::Play(
+            song.path.clone(),
+        )))
+        .height(ROW_HEIGHT)
+        .width(Length::Fill)
+        .into()
+}
+
+fn cell_text(column: &TrackColumn, song: &Song, stats: &SongStats) -> String {
+    match column {
+        TrackColumn::Title => song.title.clone(),
+        TrackColumn::Artist => song.artist.clone().unwrap_or_default(),
+        TrackColumn::Album => song.album_title.clone().unwrap_or_default(),
+        TrackColumn::Genre => song.genre.clone().unwrap_or_default(),
+        TrackColumn::Year => song.year.map(|y| y.to_string()).unwrap_or_default(),
+        TrackColumn::Track => song.index.map(|i| i.to_string()).unwrap_or_default(),
+        TrackColumn::Duration => song.duration.string_mins_secs(),
+        TrackColumn::DateAdded => format_date(song.date_added),
+        TrackColumn::PlayCount => stats.play_count.to_string(),
+        TrackColumn::Rating => String::new(),
+    }
+}
+
+/// Five clickable stars, clicking the current rating clears it
+fn rating_stars<'a>(song: &Song, rating: Option<u8>) -> Element<'a, Message> {
+    let rating = rating.unwrap_or(0);
+    let mut stars = row::with_capacity(5);
+    for star in 1..=5 {
+        let new_rating = if star == rating { None } else { Some(star) };
+        stars = stars.push(
+            button::text(if star <= rating { "★" } else { "☆" })
+                .on_press(Message::SetRating(song.path.clone(), new_rating))
+                .padding(0),
+        );
+    }
+    stars.into()
+}
+
+/// Formats a time as YYYY-MM-DD (UTC)
+fn format_date(time: SystemTime) -> String {
+    let Ok(since_epoch) = time.duration_since(UNIX_EPOCH) else {
+        return String::new();
+    };
+    // Convert days since the epoch into a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (since_epoch.as_secs() / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -1,41 +1,209 @@
-// use crate::HEIGHT;
+use crate::HEIGHT;
 use crate::app::Message;
+use crate::channel_tools::{ChannelSettings, CrossfeedLevel};
+use crate::crossfade::{CrossfadeCurve, CrossfadeSettings, MAX_CROSSFADE_SECONDS};
+use crate::equalizer::{EqualizerPreset, EqualizerSettings};
 // use crate::page::albums_page::Album;
+use crate::replay_gain::{ReplayGainMode, ReplayGainSettings};
 use crate::song::Song;
+use crate::song_library::SongLibrary;
+use crate::sort_key::group_key;
+use crate::speed::{MAX_SPEED, MIN_SPEED, SpeedMode, SpeedSettings};
+use crate::virtual_list::{self, ScrollState};
 use cosmic::Element;
 use cosmic::iced::Length;
+use cosmic::iced::mouse::ScrollDelta;
+use cosmic::iced::widget::{self, scrollable::Viewport};
 use cosmic::iced_core::Alignment;
 use cosmic::theme;
 use cosmic::widget::*;
-use rodio::Decoder;
-use rodio::stream::OutputStream;
+use effects::SharedEffects;
+use equalizer_panel::{EqualizerMessage, EqualizerPanel};
+use loader::{LoadError, LoadJob, spawn_loader};
+use rodio::source::SeekError;
+use rodio::stream::{OutputStream, StreamError};
+use shuffle::ShuffleMode;
+use track_source::{EntryId, TailSlot, TrackEvent, TrackPosition, Transition};
 // use std::borrow::Cow;
 use std::f32::consts::PI;
-use std::fs::File;
-use std::io::BufReader;
-use std::mem;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::Duration;
+mod effects;
+mod equalizer_panel;
+mod loader;
+mod shuffle;
+mod tempo;
+mod track_source;
 
 #[derive(Debug, Clone)]
 pub enum PlayerMessage {
     PlaySong(Song),              // Plays a specific song, clearing the playlist
     PlaySongs(Vec<Song>, usize), // Plays an album, clearing the playlist
-    Play,                        // Start playback
-    Pause,                       // Stop playback, keeping playlist
-    Update,                      // Updates the playing song and the progress
-    ProgressSlider(f32), // Updates the sink to play the current song at the appropriate time
-    Skip,                // Skips one song
-    Previous,            // Goes to the previous song
+    ShuffleSongs(Vec<Song>), // Plays the songs in random order, leaving the shuffle mode as it is
+    JumpTo(usize),           // Plays the song at the index, keeping the playlist
+    AddToQueue(Vec<Song>),   // Adds songs to the end of the playlist
+    PlayNext(Vec<Song>),     // Adds songs right after the current song
+    Play,                    // Start playback
+    Pause,                   // Stop playback, keeping playlist
+    Update,                  // Updates the playing song and the progress
+    ProgressSlider(f32),     // Updates the sink to play the current song at the appropriate time
+    Skip,                    // Skips one song
+    Previous,                // Goes to the previous song
+    PlaylistScrolled(Viewport),
+    RemoveFromQueue(usize), // Removes the song at the index from the playlist
+    MoveInQueue(usize, usize), // Moves the song at the first index to the second
+    ClearUpcoming,          // Removes every song after the current one
+    RemovePlayed,           // Removes every song before the current one
+    DragStart(usize),       // Picks up a playlist entry to reorder it
+    DragOver(usize),        // The dragged entry is over the entry at the index
+    DragEnd,                // Drops the dragged entry where it is
+    DragCancel,             // Puts the dragged entry back when the mouse leaves the queue
+    SetShuffle(ShuffleMode), // Shuffles the playlist around the current song, or puts it back in order
+    CycleRepeat,             // Switches between repeat off, repeat all and repeat one
+    ToggleStopAfter,         // Pauses once the current song ends, only for this song
+    SetVolume(f32),          // Sets the volume slider's position, from 0 to 1
+    ChangeVolume(f32), // Raises or lowers the volume by a number of steps, from the wheel or keyboard
+    SaveSettings,      // A settings slider was let go
+    ToggleMute,
+    ToggleSoundSettings, // Shows or hides the sound settings
+    SetReplayGainMode(ReplayGainMode),
+    SetPreamp(f32), // ReplayGain preamp in dB
+    TogglePreventClipping,
+    Equalizer(EqualizerMessage),
+    SetCrossfade(f32), // Crossfade duration in seconds
+    SetCrossfadeCurve(CrossfadeCurve),
+    SetSpeed(f32), // Playback speed, 1 is normal
+    SetSpeedMode(SpeedMode),
+    SetBalance(f32), // From -1 for left to 1 for right
+    ToggleMono,
+    ToggleSwapChannels,
+    ToggleCrossfeed,
+    SetCrossfeedLevel(CrossfeedLevel),
+}
+
+/// How far into a song Previous restarts it instead of going back a song
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+/// Songs after the current one kept decoded in the sink, so they start without a gap
+const PRELOAD: usize = 1;
+
+/// Volume change for one wheel line or key press, on the slider's scale
+const VOLUME_STEP: f32 = 0.05;
+/// Loudness range of the volume slider in decibels, below it the slider goes silent
+const VOLUME_RANGE_DB: f32 = 50.0;
+/// Range of the ReplayGain preamp slider in dB, either way
+const PREAMP_RANGE_DB: f32 = 15.0;
+
+/// What plays once a song ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Starts over from the first song after the last one
+    All,
+    /// Plays the current song again
+    One,
+}
+impl RepeatMode {
+    fn next(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+    fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "Repeat off",
+            RepeatMode::All => "Repeat all",
+            RepeatMode::One => "Repeat one",
+        }
+    }
+}
+
+/// Something that went wrong while playing, for the app to show
+#[derive(Debug)]
+pub enum PlayerError {
+    /// The song was skipped as it could not be played
+    Unplayable(PathBuf, LoadError),
+    /// Playback stays silent without an output device
+    NoOutput(StreamError),
+    Seek(SeekError),
+}
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerError::Unplayable(path, error) => {
+                let name = path.file_name().unwrap_or(path.as_os_str());
+                write!(f, "Skipped {}: {}", name.to_string_lossy(), error)
+            }
+            PlayerError::NoOutput(error) => write!(f, "Could not open audio output: {}", error),
+            PlayerError::Seek(error) => write!(f, "Could not seek: {}", error),
+        }
+    }
+}
+impl std::error::Error for PlayerError {}
+
+/// A song in the queue, the id tells apart copies of the same song
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub id: EntryId,
+    pub song: Song,
 }
 
 pub struct Player {
     song_index: usize,
-    playlist: Vec<Song>,
+    playlist: Vec<QueueEntry>,
     playing: bool,
-    shuffle: bool,
+    shuffle: ShuffleMode,
+    /// Entry ids in the order they were queued, to go back to when shuffle is turned off
+    original_order: Vec<EntryId>,
+    repeat: RepeatMode,
+    /// Pause once the current song ends, cleared when it does
+    stop_after_current: bool,
+    /// The last song played to its end, so songs added next start playing
+    queue_ended: bool,
+    /// Volume slider position from 0 to 1, mapped to a gain by `volume_gain`
+    volume: f32,
+    muted: bool,
+    /// Applies to songs as they are loaded, so changes are heard from the next song
+    replay_gain: ReplayGainSettings,
+    show_sound_settings: bool,
+    equalizer: EqualizerPanel,
+    /// Effect settings read by the playing songs
+    effects: SharedEffects,
+    crossfade: CrossfadeSettings,
+    /// Where a song fading out leaves its end for the next song to play
+    tails: TailSlot,
+    speed: SpeedSettings,
+    channels: ChannelSettings,
     progress: Duration,
-    _stream_handle: OutputStream, // Keep stream handle alive to continue playback
-    sink: rodio::Sink,            // Keep audio sink alive to continue playback
+    stream_handle: Option<OutputStream>, // Keep stream handle alive to continue playback, None without an output device
+    sink: Arc<rodio::Sink>, // Keep audio sink alive to continue playback, shared with the loader
+    playlist_scroll: ScrollState,
+    /// Playlist entry being dragged, and the entry it's over
+    drag: Option<(usize, usize)>,
+    /// Track starts and ends reported by the sources in the sink
+    events: Receiver<TrackEvent>,
+    position: TrackPosition,
+    next_entry_id: EntryId,
+    loader: Sender<LoadJob>,
+    /// Entries sent to the sink, in order, starting with the current one
+    loaded: Vec<LoadedEntry>,
+    /// Errors since the app last took them, to show to the user
+    errors: Vec<PlayerError>,
+    /// Songs that started playing since the app last took them, to count their plays
+    started: Vec<PathBuf>,
+}
+
+/// An entry that is in the sink or on its way there
+struct LoadedEntry {
+    id: EntryId,
+    cancelled: Arc<AtomicBool>,
+    transition: Arc<Transition>,
 }
 
 impl Player {
@@ -43,45 +211,251 @@ impl Player {
         song_index: impl Into<usize>,
         playlist: Vec<Song>,
         playing: bool,
-        shuffle: bool,
+        shuffle: ShuffleMode,
         progress: Duration,
-        _stream_handle: OutputStream,
+        stream_handle: Option<OutputStream>,
         sink: rodio::Sink,
     ) -> Player {
-        Self {
+        let (events_sender, events) = mpsc::channel();
+        let sink = Arc::new(sink);
+        let position = TrackPosition::default();
+        let effects = SharedEffects::default();
+        let tails = TailSlot::default();
+        let mut player = Self {
             song_index: song_index.into(),
-            playlist,
+            playlist: vec![],
             playing,
             shuffle,
+            original_order: vec![],
+            repeat: RepeatMode::Off,
+            stop_after_current: false,
+            queue_ended: false,
+            volume: 1.0,
+            muted: false,
+            replay_gain: ReplayGainSettings::default(),
+            show_sound_settings: false,
+            equalizer: EqualizerPanel::default(),
+            effects: effects.clone(),
+            crossfade: CrossfadeSettings::default(),
+            tails: tails.clone(),
+            speed: SpeedSettings::default(),
+            channels: ChannelSettings::default(),
             progress,
-            _stream_handle,
+            stream_handle,
+            loader: spawn_loader(
+                sink.clone(),
+                events_sender,
+                position.clone(),
+                effects,
+                tails,
+            ),
             sink,
-        }
+            playlist_scroll: ScrollState::default(),
+            drag: None,
+            events,
+            position,
+            next_entry_id: 0,
+            loaded: vec![],
+            errors: vec![],
+            started: vec![],
+        };
+        player.playlist = player.new_entries(playlist);
+        player.remember_order();
+        player
     }
+    /// Plays through the default output device
+    /// Without one the player still works, silently, and reports the error
     pub fn default() -> Player {
-        let stream_handle = rodio::OutputStreamBuilder::open_default_stream()
-            .expect("Could not create audio sink: ");
-        Self {
-            song_index: 0,
-            playlist: vec![],
-            playing: false,
-            shuffle: false,
-            progress: Duration::from_secs(0),
-            sink: rodio::Sink::connect_new(&stream_handle.mixer()),
-            _stream_handle: stream_handle,
-        }
+        let (stream_handle, sink, error) = match rodio::OutputStreamBuilder::open_default_stream() {
+            Ok(stream_handle) => {
+                let sink = rodio::Sink::connect_new(stream_handle.mixer());
+                (Some(stream_handle), sink, None)
+            }
+            Err(error) => {
+                eprintln!("Could not open audio output: {}", error);
+                // Nothing reads from the queue, so songs never start
+                let (sink, _output) = rodio::Sink::new();
+                (None, sink, Some(PlayerError::NoOutput(error)))
+            }
+        };
+        let mut player = Player::new(
+            0usize,
+            vec![],
+            false,
+            ShuffleMode::Off,
+            Duration::ZERO,
+            stream_handle,
+            sink,
+        );
+        player.errors.extend(error);
+        player
     }
     /// Handles cosmic messages
-    pub fn update(&mut self, message: PlayerMessage) {
+    pub fn update(&mut self, message: PlayerMessage, library: &SongLibrary) {
         match message {
             PlayerMessage::PlaySong(song) => {
                 self.play_song(song);
             }
-            PlayerMessage::PlaySongs(mut songs, index) => {
-                self.clear_playlist();
-                self.add_to_playlist(&mut songs);
+            PlayerMessage::PlaySongs(songs, index) => {
+                self.playlist = self.new_entries(songs);
+                self.song_index = index;
+                if self.shuffle != ShuffleMode::Off {
+                    self.remember_order();
+                    self.shuffle_playlist(library);
+                }
+                self.play_index(self.song_index);
+            }
+            PlayerMessage::ShuffleSongs(songs) => {
+                self.playlist = self.new_entries(songs);
+                // Turning shuffle off later still puts them back in the order they came in
+                self.remember_order();
+                let playlist = std::mem::take(&mut self.playlist);
+                self.playlist = shuffle::shuffle(playlist, None, ShuffleMode::Tracks, library);
+                self.song_index = 0;
+                self.play_index(self.song_index);
+            }
+            PlayerMessage::JumpTo(index) => {
                 self.play_index(index);
             }
+            PlayerMessage::AddToQueue(songs) => {
+                // Start playing if there was nothing left to play
+                let was_finished = self.queue_ended || self.playlist.is_empty();
+                if was_finished {
+                    self.song_index = self.playlist.len();
+                }
+                self.add_to_playlist(songs, library);
+                if was_finished {
+                    self.play();
+                }
+            }
+            PlayerMessage::PlayNext(songs) => {
+                if self.playlist.is_empty() {
+                    self.add_to_playlist(songs, library);
+                    self.play();
+                } else {
+                    self.insert_after_current(songs);
+                }
+            }
+            PlayerMessage::RemoveFromQueue(index) => {
+                self.remove(index);
+            }
+            PlayerMessage::MoveInQueue(from, to) => {
+                self.move_song(from, to);
+            }
+            PlayerMessage::ClearUpcoming => {
+                self.playlist.truncate(self.song_index + 1);
+                shuffle::forget_removed(&mut self.original_order, &self.playlist);
+                self.fill_sink();
+            }
+            PlayerMessage::RemovePlayed => {
+                self.playlist
+                    .drain(..self.song_index.min(self.playlist.len()));
+                shuffle::forget_removed(&mut self.original_order, &self.playlist);
+                self.song_index = 0;
+            }
+            PlayerMessage::DragStart(index) => {
+                self.drag = Some((index, index));
+            }
+            PlayerMessage::DragOver(index) => {
+                if let Some((from, _)) = self.drag {
+                    self.drag = Some((from, index));
+                }
+            }
+            PlayerMessage::DragEnd => {
+                if let Some((from, to)) = self.drag.take() {
+                    self.move_song(from, to);
+                }
+            }
+            PlayerMessage::DragCancel => {
+                self.drag = None;
+            }
+            PlayerMessage::SetShuffle(mode) => {
+                self.set_shuffle(mode, library);
+            }
+            PlayerMessage::CycleRepeat => {
+                self.repeat = self.repeat.next();
+                if !self.loaded.is_empty() {
+                    self.fill_sink();
+                }
+            }
+            PlayerMessage::ToggleStopAfter => {
+                self.stop_after_current = !self.stop_after_current;
+                // Drops or brings back the preloaded song
+                if !self.loaded.is_empty() {
+                    self.fill_sink();
+                }
+            }
+            PlayerMessage::SetVolume(volume) => {
+                self.set_volume(volume);
+            }
+            PlayerMessage::ChangeVolume(steps) => {
+                self.set_volume(self.volume + steps * VOLUME_STEP);
+            }
+            PlayerMessage::ToggleMute => {
+                self.set_muted(!self.muted);
+            }
+            // Saved by the app
+            PlayerMessage::SaveSettings => {}
+            PlayerMessage::ToggleSoundSettings => {
+                self.show_sound_settings = !self.show_sound_settings;
+            }
+            PlayerMessage::SetReplayGainMode(mode) => {
+                self.replay_gain.mode = mode;
+            }
+            PlayerMessage::SetPreamp(preamp) => {
+                self.replay_gain.preamp = preamp.clamp(-PREAMP_RANGE_DB, PREAMP_RANGE_DB);
+            }
+            PlayerMessage::TogglePreventClipping => {
+                self.replay_gain.prevent_clipping = !self.replay_gain.prevent_clipping;
+            }
+            PlayerMessage::Equalizer(message) => {
+                self.equalizer.update(message);
+                self.effects.set_equalizer(self.equalizer.settings.clone());
+            }
+            PlayerMessage::SetCrossfade(duration) => {
+                self.crossfade.duration = duration.clamp(0.0, MAX_CROSSFADE_SECONDS);
+                self.update_transitions();
+            }
+            PlayerMessage::SetCrossfadeCurve(curve) => {
+                self.crossfade.curve = curve;
+                self.update_transitions();
+            }
+            PlayerMessage::SetSpeed(speed) => {
+                // Normal speed is easy to land on with the slider
+                let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+                self.speed.speed = if (speed - 1.0).abs() < 0.03 {
+                    1.0
+                } else {
+                    speed
+                };
+                self.effects.set_speed(self.speed);
+            }
+            PlayerMessage::SetSpeedMode(mode) => {
+                self.speed.mode = mode;
+                self.effects.set_speed(self.speed);
+            }
+            PlayerMessage::SetBalance(balance) => {
+                // The centre is easy to land on with the slider
+                let balance = balance.clamp(-1.0, 1.0);
+                self.channels.balance = if balance.abs() < 0.03 { 0.0 } else { balance };
+                self.effects.set_channels(self.channels);
+            }
+            PlayerMessage::ToggleMono => {
+                self.channels.mono = !self.channels.mono;
+                self.effects.set_channels(self.channels);
+            }
+            PlayerMessage::ToggleSwapChannels => {
+                self.channels.swap = !self.channels.swap;
+                self.effects.set_channels(self.channels);
+            }
+            PlayerMessage::ToggleCrossfeed => {
+                self.channels.crossfeed = !self.channels.crossfeed;
+                self.effects.set_channels(self.channels);
+            }
+            PlayerMessage::SetCrossfeedLevel(level) => {
+                self.channels.crossfeed_level = level;
+                self.effects.set_channels(self.channels);
+            }
             PlayerMessage::Play => {
                 self.play();
             }
@@ -97,69 +471,416 @@ impl Player {
             PlayerMessage::Update => {
                 self.sync();
             }
+            PlayerMessage::PlaylistScrolled(viewport) => {
+                self.playlist_scroll.update(viewport);
+            }
             PlayerMessage::ProgressSlider(progress_input) => {
-                eprintln!("Going to {:#?} seconds in source.", progress_input);
-                self.sink
-                    .try_seek(Duration::from_secs_f32(progress_input))
-                    .expect("Could not seek through given source.");
+                // Some streams can't seek, playback carries on where it was and the slider goes back to it
+                // Not shown as an error, dragging the slider would show one for every step
+                let _ = self.seek(Duration::from_secs_f32(progress_input));
                 self.sync();
             }
         }
     }
+    fn seek(&self, position: Duration) -> Result<(), PlayerError> {
+        // Without an output nothing plays, and the sink would wait forever for the seek to happen
+        if self.stream_handle.is_none() {
+            return Ok(());
+        }
+        self.sink.try_seek(position).map_err(PlayerError::Seek)
+    }
+    /// Returns the errors since the last call, to show to the user
+    pub fn take_errors(&mut self) -> Vec<PlayerError> {
+        std::mem::take(&mut self.errors)
+    }
+    /// Returns the paths of the songs that started playing since the last call
+    /// A song repeating or queued twice in a row shows up each time it starts
+    pub fn take_started(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.started)
+    }
     /// Returns whether or not the Player is playing a song
     pub fn is_playing(&self) -> bool {
         self.playing
     }
-    /// Adds the given songs to the queue
-    pub fn add_to_playlist(&mut self, songs: &mut Vec<Song>) {
-        // Add songs to sink playlist
-        for song in songs.iter() {
-            self.sink.add_song(&song);
-            // eprintln!("Adding {:#?} to playlist.", song);
+    /// Returns the volume slider position, from 0 to 1
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.apply_volume();
+    }
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.apply_volume();
+    }
+    pub fn replay_gain(&self) -> ReplayGainSettings {
+        self.replay_gain
+    }
+    pub fn set_replay_gain(&mut self, replay_gain: ReplayGainSettings) {
+        self.replay_gain = replay_gain;
+    }
+    pub fn equalizer(&self) -> (&EqualizerSettings, &[EqualizerPreset]) {
+        (&self.equalizer.settings, &self.equalizer.presets)
+    }
+    pub fn set_equalizer(&mut self, settings: EqualizerSettings, presets: Vec<EqualizerPreset>) {
+        self.equalizer = EqualizerPanel::new(settings, presets);
+        self.effects.set_equalizer(self.equalizer.settings.clone());
+    }
+    pub fn crossfade(&self) -> CrossfadeSettings {
+        self.crossfade
+    }
+    pub fn set_crossfade(&mut self, crossfade: CrossfadeSettings) {
+        self.crossfade = crossfade;
+        self.update_transitions();
+    }
+    pub fn speed(&self) -> SpeedSettings {
+        self.speed
+    }
+    pub fn set_speed(&mut self, speed: SpeedSettings) {
+        self.speed = speed;
+        self.effects.set_speed(speed);
+    }
+    pub fn channels(&self) -> ChannelSettings {
+        self.channels
+    }
+    pub fn set_channels(&mut self, channels: ChannelSettings) {
+        self.channels = channels;
+        self.effects.set_channels(channels);
+    }
+    fn apply_volume(&self) {
+        let gain = if self.muted {
+            0.0
+        } else {
+            volume_gain(self.volume)
+        };
+        self.sink.set_volume(gain);
+    }
+    /// Returns the song at the current position in the playlist
+    pub fn current_song(&self) -> Option<&Song> {
+        self.playlist.get(self.song_index).map(|entry| &entry.song)
+    }
+    /// Gives each song its own queue entry id
+    fn new_entries(&mut self, songs: Vec<Song>) -> Vec<QueueEntry> {
+        songs
+            .into_iter()
+            .map(|song| {
+                self.next_entry_id += 1;
+                QueueEntry {
+                    id: self.next_entry_id,
+                    song,
+                }
+            })
+            .collect()
+    }
+    /// Adds the given songs to the queue, shuffled among themselves when shuffle is on
+    pub fn add_to_playlist(&mut self, songs: Vec<Song>, library: &SongLibrary) {
+        let mut entries = self.new_entries(songs);
+        if self.shuffle != ShuffleMode::Off {
+            self.original_order
+                .extend(entries.iter().map(|entry| entry.id));
+            entries = shuffle::shuffle(entries, None, self.shuffle, library);
+        }
+        self.playlist.extend(entries);
+        self.fill_sink();
+    }
+    /// Inserts songs after the current one
+    pub fn insert_after_current(&mut self, songs: Vec<Song>) {
+        // After the queue ended past its end they go at the end
+        let index = (self.song_index + 1).min(self.playlist.len());
+        let entries = self.new_entries(songs);
+        // They also come after the current song once shuffle is turned off
+        if self.shuffle != ShuffleMode::Off {
+            let after = self
+                .playlist
+                .get(self.song_index)
+                .and_then(|current| self.original_order.iter().position(|id| *id == current.id))
+                .map_or(self.original_order.len(), |position| position + 1);
+            self.original_order
+                .splice(after..after, entries.iter().map(|entry| entry.id));
+        }
+        self.playlist.splice(index..index, entries);
+        self.fill_sink();
+    }
+    /// Removes the song at the index, playing the next song if it was the current one
+    pub fn remove(&mut self, index: usize) {
+        if index >= self.playlist.len() {
+            return;
+        }
+        self.playlist.remove(index);
+        shuffle::forget_removed(&mut self.original_order, &self.playlist);
+        match index_after_removal(self.song_index, index, self.playlist.len()) {
+            Some(current) => {
+                self.song_index = current;
+                self.fill_sink();
+            }
+            None => self.end_queue(),
+        }
+    }
+    /// Stops after the current song was removed from the end of the queue
+    /// The index is left past the end, so play doesn't start a song that already played
+    fn end_queue(&mut self) {
+        self.song_index = self.playlist.len();
+        self.cancel_loaded(0);
+        self.pause();
+        self.queue_ended = true;
+    }
+    /// Changes the shuffle mode, keeping the current song playing
+    pub fn set_shuffle(&mut self, mode: ShuffleMode, library: &SongLibrary) {
+        if mode == self.shuffle {
+            return;
+        }
+        if self.shuffle == ShuffleMode::Off {
+            self.original_order = self.playlist.iter().map(|entry| entry.id).collect();
+        }
+        self.shuffle = mode;
+        if mode == ShuffleMode::Off {
+            let current = self.playlist.get(self.song_index).map(|entry| entry.id);
+            shuffle::restore(&mut self.playlist, &self.original_order);
+            self.original_order.clear();
+            self.song_index = current
+                .and_then(|current| self.playlist.iter().position(|entry| entry.id == current))
+                .unwrap_or(0);
+        } else {
+            self.shuffle_playlist(library);
+        }
+        // Nothing gets loaded once the queue has ended, until play is pressed
+        if !self.loaded.is_empty() {
+            self.fill_sink();
+        }
+    }
+    /// Remembers the playlist's order to go back to when shuffle is turned off
+    fn remember_order(&mut self) {
+        if self.shuffle == ShuffleMode::Off {
+            return;
+        }
+        self.original_order = self.playlist.iter().map(|entry| entry.id).collect();
+    }
+    /// Shuffles the whole playlist with the current song first
+    fn shuffle_playlist(&mut self, library: &SongLibrary) {
+        let playlist = std::mem::take(&mut self.playlist);
+        let current = (self.song_index < playlist.len()).then_some(self.song_index);
+        self.playlist = shuffle::shuffle(playlist, current, self.shuffle, library);
+        self.song_index = 0;
+    }
+    /// Moves the song at one index to another, keeping the current song playing
+    pub fn move_song(&mut self, from: usize, to: usize) {
+        if from == to || from >= self.playlist.len() || to >= self.playlist.len() {
+            return;
+        }
+        let entry = self.playlist.remove(from);
+        self.playlist.insert(to, entry);
+        let current = self.song_index;
+        self.song_index = if from == current {
+            to
+        } else if from < current && to >= current {
+            current - 1
+        } else if from > current && to <= current {
+            current + 1
+        } else {
+            current
+        };
+        self.fill_sink();
+    }
+    /// Makes the sink hold the current song and the few after it, loading what's missing in the background
+    /// Loaded songs that are no longer wanted are cancelled, so skipping around the queue only ever loads a few songs
+    fn fill_sink(&mut self) {
+        let count = if self.stop_after_current {
+            1
+        } else {
+            1 + PRELOAD
+        };
+        let wanted: Vec<EntryId> = self
+            .upcoming(count)
+            .into_iter()
+            .map(|index| self.playlist[index].id)
+            .collect();
+        // Songs before the wanted ones were skipped
+        if let Some(first) = wanted.first()
+            && let Some(skipped) = self.loaded.iter().position(|loaded| loaded.id == *first)
+        {
+            for loaded in self.loaded.drain(..skipped) {
+                loaded.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+        // Keep what is already loaded in the right order, and replace the rest
+        let matching = self
+            .loaded
+            .iter()
+            .zip(wanted.iter())
+            .take_while(|(loaded, id)| loaded.id == **id)
+            .count();
+        self.cancel_loaded(matching);
+        for id in &wanted[matching..] {
+            let Some(index) = self.playlist.iter().position(|entry| entry.id == *id) else {
+                continue;
+            };
+            let song = &self.playlist[index].song;
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let transition = Arc::new(Transition::default());
+            let job = LoadJob {
+                path: song.path.clone(),
+                id: *id,
+                cancelled: cancelled.clone(),
+                gain: self
+                    .replay_gain
+                    .factor(&song.replay_gain, self.plays_in_album(index)),
+                transition: transition.clone(),
+            };
+            if self.loader.send(job).is_err() {
+                eprintln!("The song loader has stopped.");
+                break;
+            }
+            self.loaded.push(LoadedEntry {
+                id: *id,
+                cancelled,
+                transition,
+            });
+        }
+        self.update_transitions();
+    }
+    /// Tells the loaded songs whether to crossfade into the song loaded after them
+    /// Songs that follow each other on their album play gaplessly instead
+    fn update_transitions(&self) {
+        let upcoming = self.upcoming(self.loaded.len());
+        for (i, loaded) in self.loaded.iter().enumerate() {
+            let crossfade = upcoming
+                .get(i)
+                .zip(upcoming.get(i + 1))
+                .filter(|(before, after)| !self.follows_in_album(**before, **after))
+                .map(|_| self.crossfade)
+                .filter(|crossfade| !crossfade.duration().is_zero());
+            loaded.transition.set_crossfade(crossfade);
+        }
+    }
+    /// Indices of the songs that play one after another from the current one, following the repeat mode
+    /// With repeat one, or repeat all and a single song, the same index comes up more than once
+    fn upcoming(&self, count: usize) -> Vec<usize> {
+        let len = self.playlist.len();
+        if self.song_index >= len {
+            return vec![];
+        }
+        match self.repeat {
+            RepeatMode::Off => (self.song_index..len).take(count).collect(),
+            RepeatMode::All => (0..count).map(|i| (self.song_index + i) % len).collect(),
+            RepeatMode::One => vec![self.song_index; count],
+        }
+    }
+    /// Whether the song at the index plays as part of its album, right before or after its neighbouring track
+    fn plays_in_album(&self, index: usize) -> bool {
+        index
+            .checked_sub(1)
+            .is_some_and(|before| self.follows_in_album(before, index))
+            || self.follows_in_album(index, index + 1)
+    }
+    /// Whether the song at `after` is the next track on the album of the song at `before`
+    fn follows_in_album(&self, before: usize, after: usize) -> bool {
+        let (Some(before), Some(after)) = (self.playlist.get(before), self.playlist.get(after))
+        else {
+            return false;
+        };
+        let (before, after) = (&before.song, &after.song);
+        let same_album = match (&before.album_title, &after.album_title) {
+            (Some(a), Some(b)) => group_key(a) == group_key(b),
+            _ => false,
+        };
+        same_album
+            && before
+                .index
+                .zip(after.index)
+                .is_some_and(|(a, b)| a + 1 == b)
+    }
+    /// Cancels the loaded entries from the index onwards
+    fn cancel_loaded(&mut self, from: usize) {
+        for loaded in self.loaded.drain(from.min(self.loaded.len())..) {
+            loaded.cancelled.store(true, Ordering::Relaxed);
         }
-
-        self.playlist.append(songs);
     }
     /// Clears the playlist
     pub fn clear_playlist(&mut self) {
         self.song_index = 0;
         self.playlist = vec![];
-        self.sink.stop();
+        self.original_order.clear();
+        self.cancel_loaded(0);
     }
-    /// Updates the internal player state to sync with the sink
+    /// Updates the internal player state from the events sent by the playing sources
     pub fn sync(&mut self) {
-        // if self.song_index == 0 {
-        //     return;
-        // }
-        // eprintln!("index before: {:#?}", self.song_index);
-        let mut prior_duration = Duration::from_secs(0);
-        let pos = self.sink.get_pos();
-
-        // Loop over song durations until we reach the song before the current one, updating the internal song_index as we go
-        for (i, song) in self.playlist.iter().enumerate() {
-            let temp_duration = prior_duration + song.duration;
-            // If we get to the song we are currently playing, break from the loop
-            if temp_duration > pos {
-                break;
+        while let Ok(event) = self.events.try_recv() {
+            let id = match &event {
+                TrackEvent::Started(id) | TrackEvent::Ended(id) | TrackEvent::Failed(id, _) => *id,
+            };
+            // Ignore events from songs that were cancelled since
+            if !self.loaded.iter().any(|loaded| loaded.id == id) {
+                continue;
+            }
+            match event {
+                TrackEvent::Started(id) => {
+                    if let Some(index) = self.playlist.iter().position(|entry| entry.id == id) {
+                        self.song_index = index;
+                        self.started.push(self.playlist[index].song.path.clone());
+                        // Preload the song after this one
+                        self.fill_sink();
+                    }
+                }
+                TrackEvent::Ended(id) => {
+                    // The same entry can be loaded more than once when repeating
+                    if let Some(index) = self.loaded.iter().position(|loaded| loaded.id == id) {
+                        self.loaded.remove(index);
+                    }
+                    if self.loaded.is_empty() {
+                        // Paused, so songs loaded later don't start on their own
+                        self.pause();
+                        if self.stop_after_current {
+                            self.stop_after_current = false;
+                            // Play picks up with the song that would have come next
+                            if let Some(next) = self.upcoming(2).get(1) {
+                                self.song_index = *next;
+                                self.position.set(Duration::ZERO);
+                            }
+                        } else {
+                            self.queue_ended = true;
+                        }
+                    }
+                }
+                TrackEvent::Failed(id, error) => {
+                    // Skip songs that can't be played
+                    self.loaded.retain(|loaded| loaded.id != id);
+                    if let Some(index) = self.playlist.iter().position(|entry| entry.id == id) {
+                        let entry = self.playlist.remove(index);
+                        self.errors
+                            .push(PlayerError::Unplayable(entry.song.path, error));
+                        match index_after_removal(self.song_index, index, self.playlist.len()) {
+                            Some(current) => {
+                                self.song_index = current;
+                                self.fill_sink();
+                            }
+                            None => self.end_queue(),
+                        }
+                    }
+                }
             }
-            prior_duration = temp_duration;
-            self.song_index = i;
         }
-
-        // eprintln!("index: {:#?}", self.song_index);
-        // eprintln!("prior duration: {:#?}", prior_duration);
-        // eprintln!("pos: {:#?}", pos);
-        self.progress = pos - prior_duration;
+        self.progress = self.position.get();
     }
     /// Clears the queue and plays the given song
     pub fn play_song(&mut self, song: Song) {
-        self.song_index = 0;
-        self.sink.stop();
-        self.sink.add_song(&song);
-        self.playlist = vec![song];
-        self.play();
+        self.playlist = self.new_entries(vec![song]);
+        self.remember_order();
+        self.play_index(0);
     }
     pub fn play(&mut self) {
+        // Nothing to play once the current song was removed from the end of the queue
+        if self.song_index >= self.playlist.len() {
+            return;
+        }
+        // Start over from the current song after the end of the queue
+        if self.loaded.is_empty() {
+            self.fill_sink();
+        }
+        self.queue_ended = false;
         self.sink.play();
         self.playing = true;
     }
@@ -168,43 +889,57 @@ impl Player {
         self.playing = false;
     }
     /// Begin playing the song at the given index in the playlist
-    /// If the sink does not start from playlist index 0, this produce unexpected results
     pub fn play_index(&mut self, index: usize) {
-        // Skip all songs up to index
-        // eprintln!("{:#?}", index - self.song_index);
-        if index > 0 {
-            for _ in 0..(index - self.song_index) {
-                // eprintln!("Skipping one song.");
-                self.sink.skip_one();
-            }
-            // self.sink.skip_one();
+        if index >= self.playlist.len() {
+            return;
         }
-        // eprintln!("Playlist: {:#?}", self.playlist);
-        // eprintln!("Sink empty: {:#?}", self.sink.empty());
-        // Play
-        self.play();
-        // Set the index
+        // The playing song fades out quickly rather than cutting off, unless it keeps playing
+        if self.playing
+            && let Some(current) = self.loaded.first()
+            && current.id != self.playlist[index].id
+        {
+            current.transition.fade_on_cancel();
+        }
+        // A song that was fading into the old next one shouldn't be heard under this one
+        self.tails.clear();
         self.song_index = index;
+        self.progress = Duration::ZERO;
+        // Stopping after the song only applies to the one it was set on
+        self.stop_after_current = false;
+        self.fill_sink();
+        self.play();
     }
-    /// Begin playing the next song in the playlist
+    /// Begin playing the next song in the playlist, going back to the first one with repeat all
+    /// Skipping moves on to the next song with repeat one too
     pub fn play_next(&mut self) {
-        if self.song_index + 1 >= self.playlist.len() {
-            return;
+        if self.song_index + 1 < self.playlist.len() {
+            self.play_index(self.song_index + 1);
+        } else if self.repeat == RepeatMode::All {
+            self.play_index(0);
         }
-        self.sink.skip_one();
-        self.song_index += 1;
     }
-    /// Plays the last song in the playlist
+    /// Goes back to the previous song, or to the start of the current one once it has played for a bit
+    /// Only the previous song and the one after it are loaded, however far into the queue we are
     pub fn play_last(&mut self) {
-        if self.song_index == 0 {
-            return;
-        }
-        let new_index = self.song_index - 1;
-        let mut playlist = mem::take(&mut self.playlist);
-        self.clear_playlist();
-        self.add_to_playlist(&mut playlist);
-        self.play_index(new_index);
         self.sync();
+        if self.progress > RESTART_THRESHOLD {
+            self.restart();
+        } else if self.song_index > 0 {
+            self.play_index(self.song_index - 1);
+        } else if self.repeat == RepeatMode::All && !self.playlist.is_empty() {
+            self.play_index(self.playlist.len() - 1);
+        } else {
+            self.restart();
+        }
+    }
+    /// Plays the current song from the start
+    pub fn restart(&mut self) {
+        // Not every source can seek, loading it again works for all of them and after the queue ended
+        if self.loaded.is_empty() || self.seek(Duration::ZERO).is_err() {
+            self.cancel_loaded(0);
+            self.fill_sink();
+        }
+        self.progress = Duration::ZERO;
     }
     /// Draws the content for the music player
     /// Split into two sections, the top section which shows the current song, and the bottom section which shows the playlist
@@ -213,10 +948,49 @@ impl Player {
         // let spacing_l = cosmic::theme::spacing().space_l;
         // let spacing_s = cosmic::theme::spacing().space_xxs;
 
-        // Get the playing song or return default
-        let Some(song) = self.playlist.get(self.song_index) else {
-            return text("No song playing.").into();
+        // The queue stays up once the current song was removed from its end, only the song's part is empty
+        let playing_song = match self.current_song() {
+            Some(song) => self.playing_song(song),
+            None if self.playlist.is_empty() => return text("No song playing.").into(),
+            None => text("No song playing.").into(),
         };
+
+        let playlist_controls = row::with_capacity(2)
+            .push(
+                button::text("Clear upcoming").on_press_maybe(
+                    (self.song_index + 1 < self.playlist.len())
+                        .then_some(Message::Player(PlayerMessage::ClearUpcoming)),
+                ),
+            )
+            .push(button::text("Remove played").on_press_maybe(
+                (self.song_index > 0).then_some(Message::Player(PlayerMessage::RemovePlayed)),
+            ))
+            .spacing(spacing);
+        let playlist_container = virtual_list::list(
+            self.playlist.len(),
+            f32::from(HEIGHT + spacing),
+            &self.playlist_scroll,
+            |i| self.playlist_entry(i),
+            |viewport| Message::Player(PlayerMessage::PlaylistScrolled(viewport)),
+        );
+        // Dropping anywhere in the queue ends a drag, even between entries, and leaving it cancels the drag
+        let playlist_container = widget::mouse_area(playlist_container)
+            .on_release(Message::Player(PlayerMessage::DragEnd))
+            .on_exit(Message::Player(PlayerMessage::DragCancel));
+
+        container(
+            column::with_capacity(3)
+                .push(container(playing_song))
+                .push(playlist_controls)
+                .push(playlist_container)
+                .padding(spacing)
+                .spacing(spacing),
+        )
+        .into()
+    }
+    /// The current song's cover, progress and the playback controls
+    fn playing_song<'a>(&'a self, song: &'a Song) -> Element<'a, Message> {
+        let spacing = cosmic::theme::spacing().space_s;
         let song_image: Element<Message> = container(image(song.picture.clone()))
             .center_x(Length::Fill)
             .max_height(400)
@@ -296,63 +1070,281 @@ impl Player {
                 .spacing(spacing),
         )
         .center_x(Length::Fill);
-        let playing_song = container(
-            column::with_capacity(4)
-                .push(song_image)
-                .push(song_title)
-                .push(song_progress_widget)
-                .push(play_pause)
+        let shuffle_index = ShuffleMode::ALL
+            .iter()
+            .position(|mode| *mode == self.shuffle);
+        let repeat_class = if self.repeat == RepeatMode::Off {
+            theme::Button::Standard
+        } else {
+            theme::Button::Suggested
+        };
+        let stop_after_class = if self.stop_after_current {
+            theme::Button::Suggested
+        } else {
+            theme::Button::Standard
+        };
+        let playback_modes = container(
+            row::with_capacity(3)
+                .push(dropdown(&ShuffleMode::LABELS, shuffle_index, |i| {
+                    Message::Player(PlayerMessage::SetShuffle(ShuffleMode::ALL[i]))
+                }))
+                .push(
+                    button::text(self.repeat.label())
+                        .on_press(Message::Player(PlayerMessage::CycleRepeat))
+                        .class(repeat_class),
+                )
+                .push(
+                    button::text("Stop after this song")
+                        .on_press(Message::Player(PlayerMessage::ToggleStopAfter))
+                        .class(stop_after_class),
+                )
+                .align_y(Alignment::Center)
                 .spacing(spacing),
-        );
-
-        let mut playlist_songs: Vec<Element<Message>> = vec![];
-        for song in self.playlist.iter() {
-            let button = button::custom(song.display())
-                .on_press(Message::Player(PlayerMessage::PlaySong(song.clone())));
-            playlist_songs.push(button.into());
+        )
+        .center_x(Length::Fill);
+        let volume_slider = widget::mouse_area(
+            slider(0.0..=1.0, self.volume, |volume| {
+                Message::Player(PlayerMessage::SetVolume(volume))
+            })
+            .on_release(Message::Player(PlayerMessage::SaveSettings))
+            .step(0.01)
+            .width(Length::Fixed(150.0)),
+        )
+        .on_scroll(|delta| {
+            let lines = match delta {
+                ScrollDelta::Lines { y, .. } => y,
+                // Roughly how far a touchpad moves for one wheel line
+                ScrollDelta::Pixels { y, .. } => y / 40.0,
+            };
+            Message::Player(PlayerMessage::ChangeVolume(lines))
+        });
+        let volume = container(
+            row::with_capacity(4)
+                .push(
+                    button::text(if self.muted { "Unmute" } else { "Mute" })
+                        .on_press(Message::Player(PlayerMessage::ToggleMute))
+                        .class(if self.muted {
+                            theme::Button::Suggested
+                        } else {
+                            theme::Button::Standard
+                        }),
+                )
+                .push(volume_slider)
+                .push(text(format!("{:.0}%", self.volume * 100.0)))
+                .push(
+                    button::text("Sound")
+                        .on_press(Message::Player(PlayerMessage::ToggleSoundSettings))
+                        .class(if self.show_sound_settings {
+                            theme::Button::Suggested
+                        } else {
+                            theme::Button::Standard
+                        }),
+                )
+                .align_y(Alignment::Center)
+                .spacing(spacing),
+        )
+        .center_x(Length::Fill);
+        let mut playing_song = column::with_capacity(7)
+            .push(song_image)
+            .push(song_title)
+            .push(song_progress_widget)
+            .push(play_pause)
+            .push(playback_modes)
+            .push(volume)
+            .spacing(spacing);
+        if self.show_sound_settings {
+            playing_song = playing_song.push(self.sound_settings());
         }
-
-        let playlist_container = scrollable(
-            column::with_children(playlist_songs).spacing(spacing), // .padding(spacing),
-        );
-
+        playing_song.into()
+    }
+    /// Settings for how songs sound, shown below the volume
+    fn sound_settings(&self) -> Element<'_, Message> {
+        let spacing = cosmic::theme::spacing().space_s;
+        let mode_index = ReplayGainMode::ALL
+            .iter()
+            .position(|mode| *mode == self.replay_gain.mode);
+        let preamp = slider(
+            -PREAMP_RANGE_DB..=PREAMP_RANGE_DB,
+            self.replay_gain.preamp,
+            |preamp| Message::Player(PlayerMessage::SetPreamp(preamp)),
+        )
+        .on_release(Message::Player(PlayerMessage::SaveSettings))
+        .step(0.5)
+        .width(Length::Fixed(150.0));
+        let replay_gain = row::with_capacity(5)
+            .push(dropdown(&ReplayGainMode::LABELS, mode_index, |i| {
+                Message::Player(PlayerMessage::SetReplayGainMode(ReplayGainMode::ALL[i]))
+            }))
+            .push(text("Preamp"))
+            .push(preamp)
+            .push(text(format!("{:+.1} dB", self.replay_gain.preamp)))
+            .push(
+                toggler(self.replay_gain.prevent_clipping)
+                    .label("Prevent clipping")
+                    .on_toggle(|_| Message::Player(PlayerMessage::TogglePreventClipping)),
+            )
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let curve_index = CrossfadeCurve::ALL
+            .iter()
+            .position(|curve| *curve == self.crossfade.curve);
+        let crossfade_duration = if self.crossfade.duration() > Duration::ZERO {
+            format!("{:.1} s", self.crossfade.duration)
+        } else {
+            "Off".to_string()
+        };
+        let crossfade = row::with_capacity(4)
+            .push(text("Crossfade"))
+            .push(
+                slider(
+                    0.0..=MAX_CROSSFADE_SECONDS,
+                    self.crossfade.duration,
+                    |duration| Message::Player(PlayerMessage::SetCrossfade(duration)),
+                )
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.5)
+                .width(Length::Fixed(150.0)),
+            )
+            .push(text(crossfade_duration))
+            .push(dropdown(&CrossfadeCurve::LABELS, curve_index, |i| {
+                Message::Player(PlayerMessage::SetCrossfadeCurve(CrossfadeCurve::ALL[i]))
+            }))
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let speed_mode_index = SpeedMode::ALL
+            .iter()
+            .position(|mode| *mode == self.speed.mode);
+        let speed = row::with_capacity(4)
+            .push(text("Speed"))
+            .push(
+                slider(MIN_SPEED..=MAX_SPEED, self.speed.speed, |speed| {
+                    Message::Player(PlayerMessage::SetSpeed(speed))
+                })
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.05)
+                .width(Length::Fixed(150.0)),
+            )
+            .push(text(format!("{:.2}×", self.speed.speed())))
+            .push(dropdown(&SpeedMode::LABELS, speed_mode_index, |i| {
+                Message::Player(PlayerMessage::SetSpeedMode(SpeedMode::ALL[i]))
+            }))
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let balance_label = match self.channels.balance {
+            0.0 => "Centre".to_string(),
+            balance if balance < 0.0 => format!("L {:.0}%", -balance * 100.0),
+            balance => format!("R {:.0}%", balance * 100.0),
+        };
+        let crossfeed_index = CrossfeedLevel::ALL
+            .iter()
+            .position(|level| *level == self.channels.crossfeed_level);
+        let balance = row::with_capacity(3)
+            .push(text("Balance"))
+            .push(
+                slider(-1.0..=1.0, self.channels.balance, |balance| {
+                    Message::Player(PlayerMessage::SetBalance(balance))
+                })
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.05)
+                .width(Length::Fixed(150.0)),
+            )
+            .push(text(balance_label))
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let channels = row::with_capacity(4)
+            .push(
+                toggler(self.channels.mono)
+                    .label("Mono")
+                    .on_toggle(|_| Message::Player(PlayerMessage::ToggleMono)),
+            )
+            .push(
+                toggler(self.channels.swap)
+                    .label("Swap left and right")
+                    .on_toggle(|_| Message::Player(PlayerMessage::ToggleSwapChannels)),
+            )
+            .push(
+                toggler(self.channels.crossfeed)
+                    .label("Headphone crossfeed")
+                    .on_toggle(|_| Message::Player(PlayerMessage::ToggleCrossfeed)),
+            )
+            .push(dropdown(&CrossfeedLevel::LABELS, crossfeed_index, |i| {
+                Message::Player(PlayerMessage::SetCrossfeedLevel(CrossfeedLevel::ALL[i]))
+            }))
+            .align_y(Alignment::Center)
+            .spacing(spacing);
         container(
-            column::with_capacity(2)
-                .push(playing_song)
-                .push(playlist_container)
-                .padding(spacing)
+            column::with_capacity(6)
+                .push(replay_gain)
+                .push(crossfade)
+                .push(speed)
+                .push(balance)
+                .push(channels)
+                .push(self.equalizer.view())
                 .spacing(spacing),
         )
+        .center_x(Length::Fill)
         .into()
     }
+    /// A playlist row with a handle to drag it by and a button to remove it
+    fn playlist_entry(&self, index: usize) -> Element<'_, Message> {
+        let spacing = cosmic::theme::spacing().space_s;
+        let song = &self.playlist[index].song;
+        // Drop targets are highlighted while an entry is dragged over them
+        let class = match self.drag {
+            Some((from, over)) if over == index && from != index => theme::Button::Suggested,
+            _ if index == self.song_index => theme::Button::Suggested,
+            _ => theme::Button::Standard,
+        };
+        let handle = widget::mouse_area(
+            container(text("≡"))
+                .center_y(Length::Fill)
+                .padding([0, spacing]),
+        )
+        .on_press(Message::Player(PlayerMessage::DragStart(index)));
+        let entry = row::with_capacity(3)
+            .push(handle)
+            .push(
+                button::custom(song.display())
+                    .on_press(Message::Player(PlayerMessage::JumpTo(index)))
+                    .class(class)
+                    .width(Length::Fill),
+            )
+            .push(
+                container(
+                    button::text("✕")
+                        .on_press(Message::Player(PlayerMessage::RemoveFromQueue(index))),
+                )
+                .center_y(Length::Fill),
+            )
+            .spacing(spacing)
+            .height(HEIGHT);
+        widget::mouse_area(entry)
+            .on_enter(Message::Player(PlayerMessage::DragOver(index)))
+            .into()
+    }
 }
 
-trait SinkSongExt {
-    fn add_song(&mut self, song: &Song);
+/// Where the current song is after the entry at `removed` was taken out of the queue, None when it was the current one and nothing came after it
+fn index_after_removal(current: usize, removed: usize, len: usize) -> Option<usize> {
+    if removed < current {
+        Some(current - 1)
+    } else if removed > current || current < len {
+        // The song after the removed one moved up into its place
+        Some(current)
+    } else {
+        None
+    }
 }
 
-impl SinkSongExt for rodio::Sink {
-    fn add_song(&mut self, song: &Song) {
-        // Load the song file into memory
-        let file_unbuf = File::open(song.path.clone()).unwrap();
-        let song_length = file_unbuf.metadata().unwrap().len();
-        let song_file = BufReader::new(file_unbuf);
-        // Decode that sound file into a rodio source
-        let source = Decoder::builder()
-            .with_data(song_file)
-            .with_byte_len(song_length)
-            .with_seekable(true)
-            .with_coarse_seek(true)
-            .with_gapless(true)
-            .build()
-            .unwrap();
-        self.append(source);
-
-        eprintln!("Adding {:#?} to playlist.", song.title);
+/// Maps the volume slider to a gain that sounds even across the slider, as loudness is heard logarithmically
+fn volume_gain(volume: f32) -> f32 {
+    if volume <= 0.0 {
+        return 0.0;
     }
+    10f32.powf((volume - 1.0) * VOLUME_RANGE_DB / 20.0)
 }
 
-trait DurationDisplayExt {
+pub(crate) trait DurationDisplayExt {
     /// Returns a string as minutes:seconds
     fn string_mins_secs(&self) -> String;
 }
@@ -372,3 +1364,33 @@ impl DurationDisplayExt for Duration {
         min.to_string() + ":" + &sec_text
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_before_or_after_the_current_song() {
+        assert_eq!(index_after_removal(3, 1, 5), Some(2));
+        assert_eq!(index_after_removal(3, 4, 5), Some(3));
+        // The next song takes the removed current one's place
+        assert_eq!(index_after_removal(3, 3, 5), Some(3));
+    }
+
+    #[test]
+    fn removing_the_last_song_while_it_plays_ends_the_queue() {
+        // The song before has already played, it isn't played again
+        assert_eq!(index_after_removal(4, 4, 4), None);
+        assert_eq!(index_after_removal(0, 0, 0), None);
+        // Once ended, removing earlier songs keeps it past the end
+        assert_eq!(index_after_removal(4, 1, 3), Some(3));
+    }
+
+    #[test]
+    fn last_song_failing_to_load_ends_the_queue() {
+        // Skipped the way a removed song is, the song before it doesn't play again
+        assert_eq!(index_after_removal(2, 2, 2), None);
+        // A preloaded song after the current one failing leaves the current one playing
+        assert_eq!(index_after_removal(1, 2, 2), Some(1));
+    }
+}
@@ -0,0 +1,305 @@
+//! The sound processing every song goes through, with settings that can change while it plays
+use crate::channel_tools::{ChannelSettings, CrossfeedCoefficients};
+use crate::equalizer::EqualizerSettings;
+use crate::speed::SpeedSettings;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Frames between checks for new settings, a few milliseconds of audio
+const SETTINGS_CHECK_INTERVAL: u64 = 256;
+
+/// Effect settings shared between the player and the sources playing, which pick up changes as they play
+#[derive(Debug, Clone, Default)]
+pub struct SharedEffects {
+    equalizer: Arc<Mutex<EqualizerSettings>>,
+    speed: Arc<Mutex<SpeedSettings>>,
+    channels: Arc<Mutex<ChannelSettings>>,
+    /// Bumped on every change, so sources only lock the settings when they changed
+    version: Arc<AtomicU64>,
+}
+impl SharedEffects {
+    pub fn set_equalizer(&self, settings: EqualizerSettings) {
+        match self.equalizer.lock() {
+            Ok(mut equalizer) => *equalizer = settings,
+            // A source panicked while reading, the settings are still whole as they're only replaced
+            Err(poisoned) => *poisoned.into_inner() = settings,
+        }
+        self.version.fetch_add(1, Ordering::Release);
+    }
+    pub fn set_speed(&self, settings: SpeedSettings) {
+        match self.speed.lock() {
+            Ok(mut speed) => *speed = settings,
+            Err(poisoned) => *poisoned.into_inner() = settings,
+        }
+        self.version.fetch_add(1, Ordering::Release);
+    }
+    pub fn set_channels(&self, settings: ChannelSettings) {
+        match self.channels.lock() {
+            Ok(mut channels) => *channels = settings,
+            Err(poisoned) => *poisoned.into_inner() = settings,
+        }
+        self.version.fetch_add(1, Ordering::Release);
+    }
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+    fn equalizer(&self) -> EqualizerSettings {
+        match self.equalizer.lock() {
+            Ok(equalizer) => equalizer.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+    pub fn speed(&self) -> SpeedSettings {
+        match self.speed.lock() {
+            Ok(speed) => *speed,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+    fn channels(&self) -> ChannelSettings {
+        match self.channels.lock() {
+            Ok(channels) => *channels,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+/// Runs a source through the effects
+pub struct Effects<S> {
+    inner: S,
+    shared: SharedEffects,
+    /// Settings version the filters were made for, None before the first sample
+    version: Option<u64>,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    /// The channel of the next sample
+    channel: usize,
+    frames: u64,
+    preamp: f64,
+    /// One filter per band and channel, bands in order
+    filters: Vec<Vec<Biquad>>,
+    /// Mixes stereo frames, None when the channels are left alone
+    channel_mixer: Option<ChannelMixer>,
+    /// The right sample of a mixed frame, played after the left one
+    pending: Option<Sample>,
+}
+impl<S: Source> Effects<S> {
+    pub fn new(inner: S, shared: SharedEffects) -> Self {
+        let channels = inner.channels();
+        let sample_rate = inner.sample_rate();
+        Self {
+            inner,
+            shared,
+            version: None,
+            channels,
+            sample_rate,
+            channel: 0,
+            frames: 0,
+            preamp: 1.0,
+            filters: vec![],
+            channel_mixer: None,
+            pending: None,
+        }
+    }
+    /// Remakes the filters when the settings or the audio format changed, at the start of a frame
+    fn update_settings(&mut self) {
+        let version = self.shared.version();
+        let channels = self.inner.channels();
+        let sample_rate = self.inner.sample_rate();
+        if self.version == Some(version)
+            && channels == self.channels
+            && sample_rate == self.sample_rate
+        {
+            return;
+        }
+        let format_changed = channels != self.channels || sample_rate != self.sample_rate;
+        self.version = Some(version);
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+
+        let equalizer = self.shared.equalizer();
+        self.preamp = f64::from(equalizer.preamp_factor());
+        let bands = equalizer.bands();
+        // Filters keep their state when only their settings change, so changes don't click
+        if format_changed || bands.len() != self.filters.len() {
+            self.filters = bands
+                .iter()
+                .map(|_| vec![Biquad::default(); usize::from(channels)])
+                .collect();
+        }
+        for (band, filters) in bands.iter().zip(&mut self.filters) {
+            let coefficients = band.coefficients(sample_rate);
+            for filter in filters {
+                filter.coefficients = coefficients;
+            }
+        }
+
+        // Only stereo has a left and right to mix
+        let settings = self.shared.channels();
+        self.channel_mixer = match &self.channel_mixer {
+            _ if channels != 2 || !settings.is_active() => None,
+            // Keep the crossfeed's state, so toggling the other settings doesn't click
+            Some(mixer) if !format_changed => Some(mixer.with_settings(settings, sample_rate)),
+            _ => Some(ChannelMixer::new(settings, sample_rate)),
+        };
+    }
+    fn equalize(&mut self, sample: Sample, channel: usize) -> Sample {
+        if self.filters.is_empty() && self.preamp == 1.0 {
+            return sample;
+        }
+        let mut value = f64::from(sample) * self.preamp;
+        for filters in &mut self.filters {
+            if let Some(filter) = filters.get_mut(channel) {
+                value = filter.process(value);
+            }
+        }
+        value as Sample
+    }
+}
+
+impl<S: Source> Iterator for Effects<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if let Some(sample) = self.pending.take() {
+            return Some(sample);
+        }
+        if self.channel == 0 {
+            if self.version.is_none() || self.frames.is_multiple_of(SETTINGS_CHECK_INTERVAL) {
+                self.update_settings();
+            }
+            self.frames += 1;
+            // Mixing needs the whole frame, so it's read at once
+            if self.channel_mixer.is_some() {
+                let left = self.inner.next()?;
+                let left = self.equalize(left, 0);
+                let Some(right) = self.inner.next() else {
+                    return Some(left);
+                };
+                let right = self.equalize(right, 1);
+                if let Some(mixer) = &mut self.channel_mixer {
+                    let (left, right) = mixer.process(left, right);
+                    self.pending = Some(right);
+                    return Some(left);
+                }
+            }
+        }
+        let sample = self.inner.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % usize::from(self.channels.max(1));
+        Some(self.equalize(sample, channel))
+    }
+}
+
+impl<S: Source> Source for Effects<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.inner.current_span_len()
+    }
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+    fn try_seek(&mut self, position: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(position)?;
+        // Seeking lands on a frame boundary
+        self.channel = 0;
+        self.pending = None;
+        for filter in self.filters.iter_mut().flatten() {
+            filter.reset();
+        }
+        if let Some(mixer) = &mut self.channel_mixer {
+            mixer.crossfeed_state = CrossfeedState::default();
+        }
+        Ok(())
+    }
+}
+
+/// A second order IIR filter in direct form 1
+#[derive(Debug, Clone, Default)]
+struct Biquad {
+    /// [b0, b1, b2, a1, a2]
+    coefficients: [f64; 5],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        let y = b0 * x + b1 * self.x[0] + b2 * self.x[1] - a1 * self.y[0] - a2 * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+    fn reset(&mut self) {
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+}
+
+/// Balance, mono, swapping and crossfeed of a stereo frame
+#[derive(Debug, Clone)]
+struct ChannelMixer {
+    settings: ChannelSettings,
+    crossfeed: CrossfeedCoefficients,
+    crossfeed_state: CrossfeedState,
+}
+#[derive(Debug, Clone, Copy, Default)]
+struct CrossfeedState {
+    /// Last input, low passed and high boosted samples, left and right
+    input: [f64; 2],
+    low: [f64; 2],
+    high: [f64; 2],
+}
+impl ChannelMixer {
+    fn new(settings: ChannelSettings, sample_rate: SampleRate) -> Self {
+        Self {
+            settings,
+            crossfeed: settings.crossfeed_level.coefficients(sample_rate),
+            crossfeed_state: CrossfeedState::default(),
+        }
+    }
+    fn with_settings(&self, settings: ChannelSettings, sample_rate: SampleRate) -> Self {
+        Self {
+            crossfeed_state: self.crossfeed_state,
+            ..Self::new(settings, sample_rate)
+        }
+    }
+    fn process(&mut self, left: Sample, right: Sample) -> (Sample, Sample) {
+        let (mut left, mut right) = if self.settings.swap {
+            (right, left)
+        } else {
+            (left, right)
+        };
+        if self.settings.mono {
+            let mix = (left + right) / 2.0;
+            (left, right) = (mix, mix);
+        } else if self.settings.crossfeed {
+            (left, right) = self.crossfeed(left, right);
+        }
+        let (left_gain, right_gain) = self.settings.balance_gains();
+        (left * left_gain, right * right_gain)
+    }
+    /// Each ear hears some of the other channel, low passed as the head would
+    fn crossfeed(&mut self, left: Sample, right: Sample) -> (Sample, Sample) {
+        let CrossfeedCoefficients { low, high, gain } = self.crossfeed;
+        let state = &mut self.crossfeed_state;
+        let input = [f64::from(left), f64::from(right)];
+        for (channel, input) in input.iter().enumerate() {
+            state.low[channel] = low.0 * input + low.1 * state.low[channel];
+            state.high[channel] =
+                high.0 * input + high.1 * state.input[channel] + high.2 * state.high[channel];
+        }
+        state.input = input;
+        (
+            ((state.high[0] + state.low[1]) * gain) as Sample,
+            ((state.high[1] + state.low[0]) * gain) as Sample,
+        )
+    }
+}
@@ -0,0 +1,357 @@
+//! Equalizer controls of the sound settings
+use super::PlayerMessage;
+use crate::app::Message;
+use crate::equalizer::{
+    EqualizerMode, EqualizerPreset, EqualizerSettings, FilterKind, GAIN_RANGE_DB,
+    GRAPHIC_FREQUENCIES, MAX_FREQUENCY, MIN_FREQUENCY, ParametricBand,
+};
+use cosmic::Element;
+use cosmic::iced::{Alignment, Length};
+use cosmic::widget::*;
+
+/// Width of the labels in front of the band sliders
+const LABEL_WIDTH: f32 = 70.0;
+/// Width of the values shown after the band sliders
+const VALUE_WIDTH: f32 = 70.0;
+const MIN_Q: f32 = 0.1;
+const MAX_Q: f32 = 10.0;
+/// Parametric bands above this are hard to keep track of
+const MAX_PARAMETRIC_BANDS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub enum EqualizerMessage {
+    ToggleEnabled,
+    SetMode(EqualizerMode),
+    SetPreamp(f32),
+    SetGraphicGain(usize, f32),
+    AddBand,
+    RemoveBand(usize),
+    SetBandKind(usize, FilterKind),
+    SetBandFrequency(usize, f32), // Slider position from 0 to 1, on a logarithmic scale
+    SetBandGain(usize, f32),
+    SetBandQ(usize, f32),
+    SelectPreset(usize), // Index into the built-in presets followed by the user's
+    PresetNameInput(String),
+    SavePreset,   // Saves the current settings under the typed name
+    DeletePreset, // Deletes the selected user preset
+}
+impl EqualizerMessage {
+    /// Slider drags, which are saved once the slider is let go
+    pub fn is_drag(&self) -> bool {
+        matches!(
+            self,
+            EqualizerMessage::SetPreamp(_)
+                | EqualizerMessage::SetGraphicGain(..)
+                | EqualizerMessage::SetBandFrequency(..)
+                | EqualizerMessage::SetBandGain(..)
+                | EqualizerMessage::SetBandQ(..)
+                | EqualizerMessage::PresetNameInput(_)
+        )
+    }
+}
+
+pub struct EqualizerPanel {
+    pub settings: EqualizerSettings,
+    /// The user's own presets
+    pub presets: Vec<EqualizerPreset>,
+    built_in: Vec<EqualizerPreset>,
+    /// Names of the built-in presets followed by the user's, for the dropdown
+    preset_names: Vec<String>,
+    preset_name: String,
+}
+impl Default for EqualizerPanel {
+    fn default() -> Self {
+        Self::new(EqualizerSettings::default(), vec![])
+    }
+}
+impl EqualizerPanel {
+    pub fn new(settings: EqualizerSettings, presets: Vec<EqualizerPreset>) -> Self {
+        let mut panel = Self {
+            settings,
+            presets,
+            built_in: EqualizerPreset::built_in(),
+            preset_names: vec![],
+            preset_name: String::new(),
+        };
+        panel.update_preset_names();
+        panel
+    }
+    fn update_preset_names(&mut self) {
+        self.preset_names = self
+            .built_in
+            .iter()
+            .chain(&self.presets)
+            .map(|preset| preset.name.clone())
+            .collect();
+    }
+    /// Index of the selected preset in the dropdown
+    fn selected_preset(&self) -> Option<usize> {
+        let name = self.settings.preset.as_ref()?;
+        // User presets can't share a built-in one's name, so the first match is the one
+        self.preset_names.iter().position(|preset| preset == name)
+    }
+    fn selected_user_preset(&self) -> Option<usize> {
+        self.selected_preset()?.checked_sub(self.built_in.len())
+    }
+    pub fn update(&mut self, message: EqualizerMessage) {
+        // Changing any band leaves the preset
+        let changes_bands = !matches!(
+            message,
+            EqualizerMessage::ToggleEnabled
+                | EqualizerMessage::SelectPreset(_)
+                | EqualizerMessage::PresetNameInput(_)
+                | EqualizerMessage::SavePreset
+                | EqualizerMessage::DeletePreset
+        );
+        if changes_bands {
+            self.settings.preset = None;
+        }
+        let band_count = self.settings.parametric.len();
+        match message {
+            EqualizerMessage::ToggleEnabled => {
+                self.settings.enabled = !self.settings.enabled;
+            }
+            EqualizerMessage::SetMode(mode) => {
+                self.settings.mode = mode;
+            }
+            EqualizerMessage::SetPreamp(preamp) => {
+                self.settings.preamp = preamp.clamp(-GAIN_RANGE_DB, GAIN_RANGE_DB);
+            }
+            EqualizerMessage::SetGraphicGain(band, gain) => {
+                if let Some(band) = self.settings.graphic.get_mut(band) {
+                    *band = gain.clamp(-GAIN_RANGE_DB, GAIN_RANGE_DB);
+                }
+            }
+            EqualizerMessage::AddBand => {
+                if band_count < MAX_PARAMETRIC_BANDS {
+                    self.settings.parametric.push(ParametricBand::default());
+                }
+            }
+            EqualizerMessage::RemoveBand(band) => {
+                if band < band_count {
+                    self.settings.parametric.remove(band);
+                }
+            }
+            EqualizerMessage::SetBandKind(band, kind) => {
+                if let Some(band) = self.settings.parametric.get_mut(band) {
+                    band.kind = kind;
+                }
+            }
+            EqualizerMessage::SetBandFrequency(band, position) => {
+                if let Some(band) = self.settings.parametric.get_mut(band) {
+                    band.frequency = frequency_from_position(position);
+                }
+            }
+            EqualizerMessage::SetBandGain(band, gain) => {
+                if let Some(band) = self.settings.parametric.get_mut(band) {
+                    band.gain = gain.clamp(-GAIN_RANGE_DB, GAIN_RANGE_DB);
+                }
+            }
+            EqualizerMessage::SetBandQ(band, q) => {
+                if let Some(band) = self.settings.parametric.get_mut(band) {
+                    band.q = q.clamp(MIN_Q, MAX_Q);
+                }
+            }
+            EqualizerMessage::SelectPreset(index) => {
+                let preset = self.built_in.iter().chain(&self.presets).nth(index);
+                if let Some(preset) = preset.cloned() {
+                    self.settings.apply(&preset);
+                    self.settings.enabled = true;
+                }
+            }
+            EqualizerMessage::PresetNameInput(name) => {
+                self.preset_name = name;
+            }
+            EqualizerMessage::SavePreset => {
+                let name = self.preset_name.trim().to_string();
+                if !self.can_save_preset(&name) {
+                    return;
+                }
+                let preset = EqualizerPreset::from_settings(name.clone(), &self.settings);
+                // Saving under an existing name replaces that preset
+                match self.presets.iter_mut().find(|preset| preset.name == name) {
+                    Some(existing) => *existing = preset,
+                    None => self.presets.push(preset),
+                }
+                self.settings.preset = Some(name);
+                self.preset_name.clear();
+                self.update_preset_names();
+            }
+            EqualizerMessage::DeletePreset => {
+                if let Some(index) = self.selected_user_preset() {
+                    self.presets.remove(index);
+                    self.settings.preset = None;
+                    self.update_preset_names();
+                }
+            }
+        }
+    }
+    fn can_save_preset(&self, name: &str) -> bool {
+        !name.is_empty() && !self.built_in.iter().any(|preset| preset.name == name)
+    }
+    pub fn view(&self) -> Element<'_, Message> {
+        let spacing = cosmic::theme::spacing().space_s;
+        let message =
+            |message: EqualizerMessage| Message::Player(PlayerMessage::Equalizer(message));
+        let mode_index = EqualizerMode::ALL
+            .iter()
+            .position(|mode| *mode == self.settings.mode);
+        let header = row::with_capacity(3)
+            .push(
+                toggler(self.settings.enabled)
+                    .label("Equalizer")
+                    .on_toggle(move |_| message(EqualizerMessage::ToggleEnabled)),
+            )
+            .push(dropdown(&EqualizerMode::LABELS, mode_index, move |i| {
+                message(EqualizerMessage::SetMode(EqualizerMode::ALL[i]))
+            }))
+            .push(dropdown(
+                &self.preset_names,
+                self.selected_preset(),
+                move |i| message(EqualizerMessage::SelectPreset(i)),
+            ))
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let name = self.preset_name.trim();
+        let presets = row::with_capacity(3)
+            .push(
+                text_input("Preset name", &self.preset_name)
+                    .on_input(move |name| message(EqualizerMessage::PresetNameInput(name)))
+                    .width(Length::Fixed(150.0)),
+            )
+            .push(
+                button::text("Save preset").on_press_maybe(
+                    self.can_save_preset(name)
+                        .then(|| message(EqualizerMessage::SavePreset)),
+                ),
+            )
+            .push(
+                button::text("Delete preset").on_press_maybe(
+                    self.selected_user_preset()
+                        .map(|_| message(EqualizerMessage::DeletePreset)),
+                ),
+            )
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let preamp = gain_row("Preamp".to_string(), self.settings.preamp, move |gain| {
+            message(EqualizerMessage::SetPreamp(gain))
+        });
+
+        let mut panel = column::with_capacity(4 + GRAPHIC_FREQUENCIES.len())
+            .push(header)
+            .push(presets)
+            .push(preamp)
+            .spacing(spacing);
+        match self.settings.mode {
+            EqualizerMode::Graphic => {
+                for (band, (frequency, gain)) in GRAPHIC_FREQUENCIES
+                    .iter()
+                    .zip(self.settings.graphic)
+                    .enumerate()
+                {
+                    panel = panel.push(gain_row(frequency_label(*frequency), gain, move |gain| {
+                        message(EqualizerMessage::SetGraphicGain(band, gain))
+                    }));
+                }
+            }
+            EqualizerMode::Parametric => {
+                for (index, band) in self.settings.parametric.iter().enumerate() {
+                    panel = panel.push(self.parametric_band(index, band));
+                }
+                panel = panel.push(
+                    button::text("Add band").on_press_maybe(
+                        (self.settings.parametric.len() < MAX_PARAMETRIC_BANDS)
+                            .then(|| message(EqualizerMessage::AddBand)),
+                    ),
+                );
+            }
+        }
+        panel.into()
+    }
+    /// Controls for one filter of the parametric equalizer
+    fn parametric_band(&self, index: usize, band: &ParametricBand) -> Element<'_, Message> {
+        let spacing = cosmic::theme::spacing().space_s;
+        let message =
+            |message: EqualizerMessage| Message::Player(PlayerMessage::Equalizer(message));
+        let kind_index = FilterKind::ALL.iter().position(|kind| *kind == band.kind);
+        let frequency = row::with_capacity(3)
+            .push(text(frequency_label(band.frequency)).width(Length::Fixed(LABEL_WIDTH)))
+            .push(
+                slider(
+                    0.0..=1.0,
+                    frequency_position(band.frequency),
+                    move |position| message(EqualizerMessage::SetBandFrequency(index, position)),
+                )
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.001),
+            )
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let q = row::with_capacity(3)
+            .push(text(format!("Q {:.2}", band.q)).width(Length::Fixed(LABEL_WIDTH)))
+            .push(
+                slider(MIN_Q..=MAX_Q, band.q, move |q| {
+                    message(EqualizerMessage::SetBandQ(index, q))
+                })
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.01),
+            )
+            .align_y(Alignment::Center)
+            .spacing(spacing);
+        let mut controls = column::with_capacity(4)
+            .push(
+                row::with_capacity(2)
+                    .push(dropdown(&FilterKind::LABELS, kind_index, move |i| {
+                        message(EqualizerMessage::SetBandKind(index, FilterKind::ALL[i]))
+                    }))
+                    .push(button::text("✕").on_press(message(EqualizerMessage::RemoveBand(index))))
+                    .align_y(Alignment::Center)
+                    .spacing(spacing),
+            )
+            .push(frequency)
+            .push(q)
+            .spacing(spacing);
+        if band.kind.has_gain() {
+            controls = controls.push(gain_row("Gain".to_string(), band.gain, move |gain| {
+                message(EqualizerMessage::SetBandGain(index, gain))
+            }));
+        }
+        container(controls).padding(spacing).into()
+    }
+}
+
+/// A labelled slider for a gain in dB
+fn gain_row<'a>(
+    label: String,
+    gain: f32,
+    on_change: impl Fn(f32) -> Message + 'a,
+) -> Element<'a, Message> {
+    let spacing = cosmic::theme::spacing().space_s;
+    row::with_capacity(3)
+        .push(text(label).width(Length::Fixed(LABEL_WIDTH)))
+        .push(
+            slider(-GAIN_RANGE_DB..=GAIN_RANGE_DB, gain, on_change)
+                .on_release(Message::Player(PlayerMessage::SaveSettings))
+                .step(0.5),
+        )
+        .push(text(format!("{:+.1} dB", gain)).width(Length::Fixed(VALUE_WIDTH)))
+        .align_y(Alignment::Center)
+        .spacing(spacing)
+        .into()
+}
+
+fn frequency_label(frequency: f32) -> String {
+    if frequency >= 1000.0 {
+        format!("{:.1} kHz", frequency / 1000.0)
+    } else {
+        format!("{:.0} Hz", frequency)
+    }
+}
+
+/// Frequencies are spread over their slider logarithmically, as pitch is heard
+fn frequency_from_position(position: f32) -> f32 {
+    MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf(position.clamp(0.0, 1.0))
+}
+fn frequency_position(frequency: f32) -> f32 {
+    (frequency / MIN_FREQUENCY).ln() / (MAX_FREQUENCY / MIN_FREQUENCY).ln()
+}
@@ -0,0 +1,98 @@
+//! Decodes queued songs on a background thread and appends them to the sink
+use super::effects::{Effects, SharedEffects};
+use super::tempo::Tempo;
+use super::track_source::{EntryId, TailSlot, TrackEvent, TrackPosition, TrackSource, Transition};
+use rodio::decoder::DecoderError;
+use rodio::{Decoder, Source};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// A song to decode and append to the sink
+pub struct LoadJob {
+    pub path: PathBuf,
+    pub id: EntryId,
+    /// Set when the song is no longer wanted, whether it's loaded yet or already playing
+    pub cancelled: Arc<AtomicBool>,
+    /// ReplayGain factor to play the song at
+    pub gain: f32,
+    pub transition: Arc<Transition>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Open(std::io::Error),
+    Decode(DecoderError),
+}
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Open(error) => write!(f, "Could not open file: {}", error),
+            LoadError::Decode(error) => write!(f, "Could not decode file: {}", error),
+        }
+    }
+}
+impl std::error::Error for LoadError {}
+
+/// Starts the loader thread, which runs until the returned sender is dropped
+/// Jobs are handled in order, so songs reach the sink in the order they were sent
+pub fn spawn_loader(
+    sink: Arc<rodio::Sink>,
+    events: Sender<TrackEvent>,
+    position: TrackPosition,
+    effects: SharedEffects,
+    tails: TailSlot,
+) -> Sender<LoadJob> {
+    let (jobs, job_receiver) = mpsc::channel::<LoadJob>();
+    thread::spawn(move || {
+        // The song appended last, which can fade into the next one once that's in the sink
+        let mut previous: Option<Arc<Transition>> = None;
+        for job in job_receiver {
+            if job.cancelled.load(Ordering::Relaxed) {
+                continue;
+            }
+            match decode(&job.path) {
+                Ok(source) => {
+                    let transition = job.transition.clone();
+                    // The tempo goes outside the track, which counts its position in the song's own time
+                    let track = TrackSource::new(
+                        Effects::new(source.amplify(job.gain), effects.clone()),
+                        job.id,
+                        events.clone(),
+                        position.clone(),
+                        job.cancelled,
+                        job.transition,
+                        tails.clone(),
+                    );
+                    sink.append(Tempo::new(track, effects.clone()));
+                    if let Some(previous) = previous.replace(transition) {
+                        previous.set_next_loaded();
+                    }
+                }
+                Err(error) => {
+                    eprintln!("Could not load {:?}: {}", job.path, error);
+                    let _ = events.send(TrackEvent::Failed(job.id, error));
+                }
+            }
+        }
+    });
+    jobs
+}
+
+fn decode(path: &Path) -> Result<Decoder<BufReader<File>>, LoadError> {
+    let file = File::open(path).map_err(LoadError::Open)?;
+    let length = file.metadata().map_err(LoadError::Open)?.len();
+    Decoder::builder()
+        .with_data(BufReader::new(file))
+        .with_byte_len(length)
+        .with_seekable(true)
+        .with_coarse_seek(true)
+        .with_gapless(true)
+        .build()
+        .map_err(LoadError::Decode)
+}
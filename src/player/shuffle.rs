@@ -0,0 +1,237 @@
+//! Orders the queue for the different shuffle modes
+use super::QueueEntry;
+use super::track_source::EntryId;
+use crate::song_library::SongLibrary;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    /// Every song in random order
+    Tracks,
+    /// Albums in random order, with the songs of each album in order
+    Albums,
+    /// Random order that favours highly rated songs and songs not played for a while
+    Weighted,
+}
+impl ShuffleMode {
+    pub const ALL: [ShuffleMode; 4] = [
+        ShuffleMode::Off,
+        ShuffleMode::Tracks,
+        ShuffleMode::Albums,
+        ShuffleMode::Weighted,
+    ];
+    pub const LABELS: [&'static str; 4] = [
+        "Shuffle off",
+        "Shuffle tracks",
+        "Shuffle albums",
+        "Weighted shuffle",
+    ];
+}
+
+/// Songs not played for this long get the full weight in weighted shuffle
+const WEIGHTED_RECENCY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Rating used for songs that haven't been rated
+const DEFAULT_RATING: u8 = 3;
+
+/// Returns the entries in shuffled order, with the current entry first
+pub fn shuffle(
+    mut entries: Vec<QueueEntry>,
+    current: Option<usize>,
+    mode: ShuffleMode,
+    library: &SongLibrary,
+) -> Vec<QueueEntry> {
+    let current = current.filter(|current| *current < entries.len());
+    let mut shuffled = Vec::with_capacity(entries.len());
+    match mode {
+        ShuffleMode::Off => return entries,
+        ShuffleMode::Tracks => {
+            if let Some(index) = current {
+                shuffled.push(entries.remove(index));
+            }
+            fastrand::shuffle(&mut entries);
+            shuffled.extend(entries);
+        }
+        ShuffleMode::Albums => {
+            // The rest of the current album keeps playing before moving on to another album
+            let current_album =
+                current.map(|index| (index, library.album_key(&entries[index].song)));
+            let mut albums: Vec<(Option<String>, Vec<QueueEntry>)> = vec![];
+            for (index, entry) in entries.into_iter().enumerate() {
+                if let Some((current_index, current_key)) = &current_album
+                    && (index == *current_index
+                        || index > *current_index
+                            && current_key.is_some()
+                            && library.album_key(&entry.song) == *current_key)
+                {
+                    shuffled.push(entry);
+                    continue;
+                }
+                let key = library.album_key(&entry.song);
+                // Songs without an album are shuffled on their own
+                match albums
+                    .iter_mut()
+                    .find(|(album, _)| album.is_some() && *album == key)
+                {
+                    Some((_, songs)) => songs.push(entry),
+                    None => albums.push((key, vec![entry])),
+                }
+            }
+            fastrand::shuffle(&mut albums);
+            shuffled.extend(albums.into_iter().flat_map(|(_, songs)| songs));
+        }
+        ShuffleMode::Weighted => {
+            if let Some(index) = current {
+                shuffled.push(entries.remove(index));
+            }
+            // Weighted random sampling: sort by a random number raised to 1 / weight
+            let now = SystemTime::now();
+            let mut keyed: Vec<(f64, QueueEntry)> = entries
+                .into_iter()
+                .map(|entry| {
+                    let weight = weight(library, &entry, now);
+                    (fastrand::f64().powf(1.0 / weight), entry)
+                })
+                .collect();
+            keyed.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+            shuffled.extend(keyed.into_iter().map(|(_, entry)| entry));
+        }
+    }
+    shuffled
+}
+
+/// How likely a song is to come early in weighted shuffle, always above zero
+fn weight(library: &SongLibrary, entry: &QueueEntry, now: SystemTime) -> f64 {
+    let stats = library.stats(&entry.song);
+    let rating = f64::from(stats.rating.unwrap_or(DEFAULT_RATING).min(5));
+    let since_played = stats
+        .last_played
+        .and_then(|last_played| now.duration_since(last_played).ok())
+        .unwrap_or(WEIGHTED_RECENCY)
+        .min(WEIGHTED_RECENCY);
+    let recency = since_played.as_secs_f64() / WEIGHTED_RECENCY.as_secs_f64();
+    (1.0 + rating) * (0.25 + 0.75 * recency)
+}
+
+/// Puts the entries back in their order from before shuffling
+/// Entries that were added while shuffled keep their place after the others
+pub fn restore(entries: &mut [QueueEntry], original_order: &[EntryId]) {
+    let positions: HashMap<EntryId, usize> = original_order
+        .iter()
+        .enumerate()
+        .map(|(position, id)| (*id, position))
+        .collect();
+    entries.sort_by_key(|entry| positions.get(&entry.id).copied().unwrap_or(usize::MAX));
+}
+
+/// Drops the ids of entries that were removed from the queue from the order to restore
+pub fn forget_removed(original_order: &mut Vec<EntryId>, entries: &[QueueEntry]) {
+    let ids: HashSet<EntryId> = entries.iter().map(|entry| entry.id).collect();
+    original_order.retain(|id| ids.contains(id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::Song;
+
+    fn entry(id: EntryId, album: Option<&str>) -> QueueEntry {
+        QueueEntry {
+            id,
+            song: Song::for_test(&id.to_string(), None, album),
+        }
+    }
+
+    fn queue(count: EntryId) -> Vec<QueueEntry> {
+        (0..count).map(|id| entry(id, None)).collect()
+    }
+
+    fn ids(entries: &[QueueEntry]) -> Vec<EntryId> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn current_song_comes_first() {
+        let library = SongLibrary::default();
+        for mode in [ShuffleMode::Tracks, ShuffleMode::Weighted] {
+            let shuffled = shuffle(queue(20), Some(7), mode, &library);
+            assert_eq!(shuffled[0].id, 7);
+            let mut sorted = ids(&shuffled);
+            sorted.sort();
+            assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        }
+        // An index past the end means nothing is playing
+        assert_eq!(
+            shuffle(queue(3), Some(3), ShuffleMode::Tracks, &library).len(),
+            3
+        );
+        assert_eq!(
+            ids(&shuffle(queue(5), Some(2), ShuffleMode::Off, &library)),
+            [0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn albums_stay_together() {
+        let library = SongLibrary::default();
+        let entries: Vec<QueueEntry> = (0..12)
+            .map(|id| entry(id, Some(["A", "B", "C", "D"][id as usize / 3])))
+            .collect();
+        let shuffled = ids(&shuffle(entries, None, ShuffleMode::Albums, &library));
+        for album in shuffled.chunks(3) {
+            let first = album[0] / 3 * 3;
+            assert_eq!(album, [first, first + 1, first + 2]);
+        }
+    }
+
+    #[test]
+    fn rest_of_current_album_plays_first() {
+        let library = SongLibrary::default();
+        let entries: Vec<QueueEntry> = (0..9)
+            .map(|id| entry(id, Some(["A", "B", "C"][id as usize / 3])))
+            .collect();
+        let shuffled = ids(&shuffle(entries, Some(4), ShuffleMode::Albums, &library));
+        assert_eq!(shuffled[..2], [4, 5]);
+        // Songs of the album before the current one are shuffled with the others
+        assert!(shuffled[2..].contains(&3));
+    }
+
+    #[test]
+    fn restore_undoes_shuffle() {
+        let library = SongLibrary::default();
+        let original_order: Vec<EntryId> = (0..20).collect();
+        let mut shuffled = shuffle(queue(20), Some(3), ShuffleMode::Tracks, &library);
+        restore(&mut shuffled, &original_order);
+        assert_eq!(ids(&shuffled), original_order);
+    }
+
+    #[test]
+    fn restore_after_removing_played_songs() {
+        let library = SongLibrary::default();
+        let mut original_order: Vec<EntryId> = (0..10).collect();
+        let mut shuffled = shuffle(queue(10), Some(0), ShuffleMode::Tracks, &library);
+        // Remove played drops everything before the current song
+        let removed: Vec<EntryId> = shuffled.drain(..4).map(|entry| entry.id).collect();
+        forget_removed(&mut original_order, &shuffled);
+        let mut remaining: Vec<EntryId> = (0..10).filter(|id| !removed.contains(id)).collect();
+        assert_eq!(original_order, remaining);
+        restore(&mut shuffled, &original_order);
+        assert_eq!(ids(&shuffled), remaining);
+
+        // Removing a single song
+        let gone = shuffled.remove(2).id;
+        forget_removed(&mut original_order, &shuffled);
+        remaining.retain(|id| *id != gone);
+        assert_eq!(original_order, remaining);
+    }
+
+    #[test]
+    fn restore_keeps_songs_added_while_shuffled_last() {
+        let original_order: Vec<EntryId> = vec![0, 1, 2, 3];
+        let mut entries: Vec<QueueEntry> = [2, 5, 0, 3, 4, 1].map(|id| entry(id, None)).into();
+        restore(&mut entries, &original_order);
+        assert_eq!(ids(&entries), [0, 1, 2, 3, 5, 4]);
+    }
+}
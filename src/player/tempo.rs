@@ -0,0 +1,320 @@
+//! Plays songs faster or slower, either resampled or time stretched with WSOLA
+//! It wraps the track's source from outside, so track positions stay in the song's own time
+use super::effects::SharedEffects;
+use crate::speed::{SpeedMode, SpeedSettings};
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/// Length of the pieces time stretching cuts the audio into, overlapping by half
+const GRAIN_LENGTH: Duration = Duration::from_millis(40);
+/// How far from its ideal place a piece may be taken, to line up with the audio before it
+const SEARCH_RANGE: Duration = Duration::from_millis(10);
+/// Only every few candidates and samples are compared while searching, which is plenty to line up waveforms
+const SEARCH_STEP: usize = 2;
+const COMPARE_STEP: usize = 4;
+/// Frames made at once when resampling
+const RESAMPLE_CHUNK: usize = 64;
+/// Input frames that are kept before being dropped all at once, about a second
+const DROP_THRESHOLD: usize = 48000;
+
+pub struct Tempo<S> {
+    inner: S,
+    shared: SharedEffects,
+    /// Settings version last read, None before the first sample
+    version: Option<u64>,
+    settings: SpeedSettings,
+    channels: usize,
+    /// Half a grain in frames, the distance between grains in the output
+    hop: usize,
+    search_range: usize,
+    /// Hann window over a whole grain, so overlapping halves add up to one
+    window: Vec<f32>,
+    /// Input frames read ahead of the output, interleaved
+    input: Vec<Sample>,
+    inner_ended: bool,
+    /// Frames into `input` where the output continues from
+    position: f64,
+    /// Where the next grain would ideally be taken from, when stretching
+    ideal: f64,
+    /// The second half of the last grain, windowed, waiting for the next grain to be added to it
+    /// Empty when not stretching
+    overlap: Vec<Sample>,
+    output: VecDeque<Sample>,
+    /// The channel of the next sample while passing samples straight through
+    channel: usize,
+}
+
+impl<S: Source> Tempo<S> {
+    pub fn new(inner: S, shared: SharedEffects) -> Self {
+        let mut tempo = Self {
+            inner,
+            shared,
+            version: None,
+            settings: SpeedSettings::default(),
+            channels: 1,
+            hop: 1,
+            search_range: 0,
+            window: vec![],
+            input: vec![],
+            inner_ended: false,
+            position: 0.0,
+            ideal: 0.0,
+            overlap: vec![],
+            output: VecDeque::new(),
+            channel: 0,
+        };
+        tempo.reset();
+        tempo
+    }
+    /// Forgets everything read ahead, for a new position in the song
+    fn reset(&mut self) {
+        let rate = self.inner.sample_rate() as f32;
+        self.channels = usize::from(self.inner.channels().max(1));
+        self.hop = ((GRAIN_LENGTH.as_secs_f32() * rate / 2.0) as usize).max(1);
+        self.search_range = (SEARCH_RANGE.as_secs_f32() * rate) as usize;
+        let length = self.hop * 2;
+        self.window = (0..length)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / length as f32).cos())
+            .collect();
+        self.input.clear();
+        self.inner_ended = false;
+        self.position = 0.0;
+        self.ideal = 0.0;
+        self.overlap.clear();
+        self.output.clear();
+        self.channel = 0;
+    }
+    fn update_settings(&mut self) {
+        let version = self.shared.version();
+        if self.version == Some(version) {
+            return;
+        }
+        self.version = Some(version);
+        self.settings = self.shared.speed();
+        // Leaving time stretching picks up where the last grain would have continued
+        if self.settings.mode != SpeedMode::TimeStretch || self.settings.is_normal() {
+            self.overlap.clear();
+        }
+    }
+    /// Passing samples straight through, once everything read ahead has been played
+    fn is_bypassed(&self) -> bool {
+        self.settings.is_normal()
+            && self.overlap.is_empty()
+            && self.position as usize >= self.input_frames()
+    }
+    fn input_frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+    /// Reads from the song until `frames` frames are read ahead, or the song ends
+    fn fill_input(&mut self, frames: usize) {
+        while !self.inner_ended && self.input_frames() < frames {
+            for channel in 0..self.channels {
+                match self.inner.next() {
+                    Some(sample) => self.input.push(sample),
+                    None => {
+                        self.inner_ended = true;
+                        // Complete the frame, so channels stay in place
+                        if channel > 0 {
+                            self.input
+                                .resize(self.input.len() + self.channels - channel, 0.0);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    /// The input sample, silence past either end
+    fn sample(&self, frame: isize, channel: usize) -> f32 {
+        usize::try_from(frame)
+            .ok()
+            .and_then(|frame| self.input.get(frame * self.channels + channel))
+            .copied()
+            .unwrap_or(0.0)
+    }
+    /// Makes more output, returning false once the song has ended
+    fn produce(&mut self) -> bool {
+        let speed = f64::from(self.settings.speed());
+        let produced = if self.settings.is_normal() {
+            self.flush_input()
+        } else {
+            match self.settings.mode {
+                SpeedMode::Resample => self.resample(speed),
+                SpeedMode::TimeStretch => self.stretch(speed),
+            }
+        };
+        self.drop_played_input();
+        produced
+    }
+    /// Plays what was read ahead as it is, after going back to normal speed
+    fn flush_input(&mut self) -> bool {
+        let start = self.position as usize * self.channels;
+        if start >= self.input.len() {
+            return false;
+        }
+        self.output.extend(&self.input[start..]);
+        self.input.clear();
+        self.position = 0.0;
+        true
+    }
+    /// Reads through the song at the speed, interpolating between samples
+    fn resample(&mut self, speed: f64) -> bool {
+        self.fill_input(self.position as usize + (RESAMPLE_CHUNK as f64 * speed) as usize + 3);
+        for _ in 0..RESAMPLE_CHUNK {
+            let frame = self.position.floor();
+            if frame as usize >= self.input_frames() {
+                return !self.output.is_empty();
+            }
+            let t = (self.position - frame) as f32;
+            let frame = frame as isize;
+            for channel in 0..self.channels {
+                let points = [-1, 0, 1, 2].map(|offset| self.sample(frame + offset, channel));
+                self.output.push_back(cubic(points, t));
+            }
+            self.position += speed;
+        }
+        true
+    }
+    /// Adds the next grain, taken close to where the song would be at this speed but lined up with the last one
+    fn stretch(&mut self, speed: f64) -> bool {
+        let hop = self.hop;
+        if self.overlap.is_empty() {
+            // Starts as if the last grain ended right here, so the audio carries on without a dip
+            self.position = self.position.floor();
+            self.ideal = self.position;
+            self.fill_input(self.position as usize + hop);
+            let start = self.position as isize;
+            self.overlap = (0..hop)
+                .flat_map(|i| {
+                    let weight = self.window[hop + i];
+                    (0..self.channels).map(move |channel| (i, channel, weight))
+                })
+                .map(|(i, channel, weight)| self.sample(start + i as isize, channel) * weight)
+                .collect();
+        }
+        let continuation = self.position as usize;
+        if continuation >= self.input_frames() && self.inner_ended {
+            return false;
+        }
+        let lowest = (self.ideal as usize).saturating_sub(self.search_range);
+        let highest = self.ideal as usize + self.search_range;
+        self.fill_input(highest + 2 * hop);
+        let start = self.best_match(continuation, lowest, highest);
+
+        for i in 0..hop {
+            for channel in 0..self.channels {
+                let sample = self.sample((start + i) as isize, channel) * self.window[i];
+                self.output
+                    .push_back(self.overlap[i * self.channels + channel] + sample);
+            }
+        }
+        for i in 0..hop {
+            for channel in 0..self.channels {
+                self.overlap[i * self.channels + channel] =
+                    self.sample((start + hop + i) as isize, channel) * self.window[hop + i];
+            }
+        }
+        self.position = (start + hop) as f64;
+        self.ideal += hop as f64 * speed;
+        true
+    }
+    /// The grain start between `lowest` and `highest` whose audio looks most like what follows `continuation`
+    fn best_match(&self, continuation: usize, lowest: usize, highest: usize) -> usize {
+        let mono = |frame: usize| -> f32 {
+            (0..self.channels)
+                .map(|channel| self.sample(frame as isize, channel))
+                .sum()
+        };
+        let target: Vec<f32> = (0..self.hop)
+            .step_by(COMPARE_STEP)
+            .map(|i| mono(continuation + i))
+            .collect();
+        let mut best = (self.ideal as usize, f32::MIN);
+        for start in (lowest..=highest).step_by(SEARCH_STEP) {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for (j, target) in target.iter().enumerate() {
+                let sample = mono(start + j * COMPARE_STEP);
+                correlation += target * sample;
+                energy += sample * sample;
+            }
+            let score = correlation / energy.sqrt().max(f32::EPSILON);
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+    /// Drops input that playback has moved past, in large batches so it's rarely moved around
+    fn drop_played_input(&mut self) {
+        let oldest = if self.overlap.is_empty() {
+            self.position
+        } else {
+            self.position.min(self.ideal)
+        };
+        let needed = (oldest as usize).saturating_sub(self.search_range + 2);
+        if needed < DROP_THRESHOLD || needed > self.input_frames() {
+            return;
+        }
+        self.input.drain(..needed * self.channels);
+        self.position -= needed as f64;
+        self.ideal -= needed as f64;
+    }
+}
+
+/// Catmull-Rom interpolation at `t` between the middle two of four points
+fn cubic([y0, y1, y2, y3]: [f32; 4], t: f32) -> f32 {
+    let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+    let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c = -0.5 * y0 + 0.5 * y2;
+    ((a * t + b) * t + c) * t + y1
+}
+
+impl<S: Source> Iterator for Tempo<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        loop {
+            if let Some(sample) = self.output.pop_front() {
+                return Some(sample);
+            }
+            if self.channel == 0 {
+                self.update_settings();
+            }
+            if self.is_bypassed() {
+                let sample = self.inner.next()?;
+                self.channel = (self.channel + 1) % usize::from(self.inner.channels().max(1));
+                return Some(sample);
+            }
+            if !self.produce() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<S: Source> Source for Tempo<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        if self.is_bypassed() {
+            return self.inner.current_span_len();
+        }
+        // Read ahead audio doesn't line up with the song's spans
+        None
+    }
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+    fn try_seek(&mut self, position: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(position)?;
+        self.reset();
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+//! A source wrapper that reports when tracks start and end, how far into them playback is, and fades between them
+use super::loader::LoadError;
+use crate::crossfade::{CrossfadeCurve, CrossfadeSettings, SKIP_FADE};
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Identifies one entry of the queue, the same song can be queued more than once
+pub type EntryId = u64;
+
+/// Sent from the audio thread as playback moves through the queue
+#[derive(Debug)]
+pub enum TrackEvent {
+    Started(EntryId),
+    /// The track played to its end, cancelled sources don't send this
+    /// A track fading into the next one ends when the fade starts
+    Ended(EntryId),
+    /// The song could not be loaded
+    Failed(EntryId, LoadError),
+}
+
+/// How far into the playing track playback is, written by the audio thread
+#[derive(Debug, Clone, Default)]
+pub struct TrackPosition(Arc<AtomicU64>);
+impl TrackPosition {
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+    pub fn set(&self, position: Duration) {
+        self.0.store(position.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// How a track ends, set by the player as the queue changes around it
+#[derive(Debug, Default)]
+pub struct Transition {
+    /// Crossfade into the next song, None to play to the end
+    crossfade: Mutex<Option<CrossfadeSettings>>,
+    /// Fade out quickly instead of stopping dead once cancelled
+    fade_on_cancel: AtomicBool,
+    /// The next song is in the sink, so there is something to fade into
+    next_loaded: AtomicBool,
+}
+impl Transition {
+    pub fn set_crossfade(&self, crossfade: Option<CrossfadeSettings>) {
+        match self.crossfade.lock() {
+            Ok(mut current) => *current = crossfade,
+            Err(poisoned) => *poisoned.into_inner() = crossfade,
+        }
+    }
+    pub fn fade_on_cancel(&self) {
+        self.fade_on_cancel.store(true, Ordering::Relaxed);
+    }
+    pub fn set_next_loaded(&self) {
+        self.next_loaded.store(true, Ordering::Relaxed);
+    }
+    /// The crossfade to do, if it's decided on and the audio thread doesn't have to wait for it
+    fn crossfade(&self) -> Option<CrossfadeSettings> {
+        if !self.next_loaded.load(Ordering::Relaxed) {
+            return None;
+        }
+        match self.crossfade.try_lock() {
+            Ok(crossfade) => *crossfade,
+            Err(_) => None,
+        }
+    }
+}
+
+/// The end of a track that started fading out, passed on to the track after it to play alongside
+struct Tail {
+    source: Box<dyn Source + Send>,
+    curve: CrossfadeCurve,
+    /// What's left of the track, which the fade lasts
+    length: Duration,
+}
+
+/// Holds the end of a track from when it starts fading out until the next track picks it up
+#[derive(Clone, Default)]
+pub struct TailSlot(Arc<Mutex<Option<Tail>>>);
+impl TailSlot {
+    fn put(&self, tail: Tail) {
+        match self.0.lock() {
+            Ok(mut slot) => *slot = Some(tail),
+            Err(poisoned) => *poisoned.into_inner() = Some(tail),
+        }
+    }
+    fn take(&self) -> Option<Tail> {
+        match self.0.lock() {
+            Ok(mut slot) => slot.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+    /// Drops a waiting tail, so it isn't heard under a song the user picked
+    pub fn clear(&self) {
+        self.take();
+    }
+}
+
+/// Samples between position updates, a few milliseconds of audio
+const POSITION_UPDATE_INTERVAL: u64 = 512;
+
+/// A fade counted in samples
+struct Fade {
+    curve: CrossfadeCurve,
+    done: u64,
+    length: u64,
+}
+impl Fade {
+    fn new(
+        curve: CrossfadeCurve,
+        length: Duration,
+        channels: ChannelCount,
+        rate: SampleRate,
+    ) -> Self {
+        let frames = length.as_secs_f64() * f64::from(rate);
+        Self {
+            curve,
+            done: 0,
+            length: frames as u64 * u64::from(channels),
+        }
+    }
+    /// Gains of the song fading in and the one fading out, for the next sample
+    fn advance(&mut self) -> (f32, f32) {
+        let progress = self.done as f32 / self.length.max(1) as f32;
+        self.done += 1;
+        (
+            self.curve.fade_in(progress),
+            self.curve.fade_in(1.0 - progress),
+        )
+    }
+    fn is_done(&self) -> bool {
+        self.done >= self.length
+    }
+}
+
+/// The previous track's end playing under the start of this one
+struct Mix {
+    tail: UniformSourceIterator<Box<dyn Source + Send>>,
+    fade: Fade,
+}
+
+pub struct TrackSource<S> {
+    /// Taken when the track hands its end on to the next one
+    inner: Option<S>,
+    id: EntryId,
+    events: Sender<TrackEvent>,
+    position: TrackPosition,
+    /// Ends the source, without events, when set
+    cancelled: Arc<AtomicBool>,
+    transition: Arc<Transition>,
+    tails: TailSlot,
+    /// The format of the track, for after it handed its end on
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    started: bool,
+    ended: bool,
+    /// Position of the last seek, samples are counted from there
+    offset: Duration,
+    samples: u64,
+    /// Samples from the last seek at which the track starts fading into the next one
+    fade_out_at: Option<u64>,
+    fade_in: Option<Mix>,
+    /// Fading out after being cancelled
+    stopping: Option<Fade>,
+}
+impl<S: Source + Send + 'static> TrackSource<S> {
+    pub fn new(
+        inner: S,
+        id: EntryId,
+        events: Sender<TrackEvent>,
+        position: TrackPosition,
+        cancelled: Arc<AtomicBool>,
+        transition: Arc<Transition>,
+        tails: TailSlot,
+    ) -> Self {
+        let channels = inner.channels();
+        let sample_rate = inner.sample_rate();
+        Self {
+            inner: Some(inner),
+            id,
+            events,
+            position,
+            cancelled,
+            transition,
+            tails,
+            channels,
+            sample_rate,
+            started: false,
+            ended: false,
+            offset: Duration::ZERO,
+            samples: 0,
+            fade_out_at: None,
+            fade_in: None,
+            stopping: None,
+        }
+    }
+    fn samples_per_second(&self) -> u64 {
+        u64::from(self.channels()) * u64::from(self.sample_rate())
+    }
+    fn current_position(&self) -> Duration {
+        let samples_per_second = self.samples_per_second();
+        if samples_per_second == 0 {
+            return self.offset;
+        }
+        self.offset + Duration::from_secs_f64(self.samples as f64 / samples_per_second as f64)
+    }
+    /// Works out when to start fading into the next track, from the current settings
+    fn update_fade_out(&mut self) {
+        let total = self.inner.as_ref().and_then(|inner| inner.total_duration());
+        self.fade_out_at = match (total, self.transition.crossfade()) {
+            (Some(total), Some(crossfade)) if !crossfade.duration().is_zero() => {
+                let start = total.saturating_sub(crossfade.duration());
+                let frames =
+                    start.saturating_sub(self.offset).as_secs_f64() * f64::from(self.sample_rate());
+                Some(frames as u64 * u64::from(self.channels()))
+            }
+            _ => None,
+        };
+    }
+    /// Passes the rest of the track on to the next one and ends, at a frame boundary
+    fn hand_off(&mut self) {
+        // The next song may have been taken out of the sink since
+        let Some(crossfade) = self.transition.crossfade() else {
+            self.fade_out_at = None;
+            return;
+        };
+        let Some(inner) = self.inner.take() else {
+            return;
+        };
+        let length = inner
+            .total_duration()
+            .unwrap_or_default()
+            .saturating_sub(self.current_position());
+        self.tails.put(Tail {
+            source: Box::new(inner),
+            curve: crossfade.curve,
+            length,
+        });
+    }
+    /// Plays the end of the previous track under the start of this one, if it handed one on
+    fn pick_up_tail(&mut self) {
+        let Some(tail) = self.tails.take() else {
+            return;
+        };
+        let (channels, sample_rate) = (self.channels(), self.sample_rate());
+        self.fade_in = Some(Mix {
+            tail: UniformSourceIterator::new(tail.source, channels, sample_rate),
+            fade: Fade::new(tail.curve, tail.length, channels, sample_rate),
+        });
+    }
+    /// Starts fading out if the player cancelled the track and asked for a fade
+    /// Returns whether the track should end straight away instead
+    fn check_cancelled(&mut self) -> bool {
+        if self.stopping.is_some() || !self.cancelled.load(Ordering::Relaxed) {
+            return false;
+        }
+        if !self.fades_when_cancelled() {
+            return true;
+        }
+        self.stopping = Some(Fade::new(
+            CrossfadeCurve::Linear,
+            SKIP_FADE,
+            self.channels(),
+            self.sample_rate(),
+        ));
+        false
+    }
+    /// Only a track that is playing is heard fading out
+    fn fades_when_cancelled(&self) -> bool {
+        self.started && self.transition.fade_on_cancel.load(Ordering::Relaxed)
+    }
+    /// Mixes in the previous track's end and applies the fades
+    fn apply_fades(&mut self, sample: Sample) -> Sample {
+        let mut sample = sample;
+        if let Some(mix) = &mut self.fade_in {
+            let (gain, tail_gain) = mix.fade.advance();
+            // The tail can run out a little early when its length was estimated
+            sample = sample * gain + mix.tail.next().unwrap_or(0.0) * tail_gain;
+            if mix.fade.is_done() {
+                self.fade_in = None;
+            }
+        }
+        if let Some(stopping) = &mut self.stopping {
+            sample *= stopping.advance().1;
+        }
+        sample
+    }
+}
+
+impl<S: Source + Send + 'static> Iterator for TrackSource<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if self.check_cancelled() {
+            return None;
+        }
+        if let Some(stopping) = &self.stopping
+            && stopping.is_done()
+        {
+            return None;
+        }
+        let at_frame_start = self
+            .samples
+            .is_multiple_of(u64::from(self.channels().max(1)));
+        if !self.started {
+            self.started = true;
+            self.position.set(self.current_position());
+            self.pick_up_tail();
+            self.update_fade_out();
+            // The player may be gone while the audio thread finishes, that's fine
+            let _ = self.events.send(TrackEvent::Started(self.id));
+        } else if at_frame_start
+            && self.stopping.is_none()
+            && self.fade_out_at.is_some_and(|at| self.samples >= at)
+        {
+            self.hand_off();
+        }
+        match self.inner.as_mut().and_then(|inner| inner.next()) {
+            Some(sample) => {
+                self.samples += 1;
+                // A cancelled track fading out no longer counts as playing
+                if self.samples.is_multiple_of(POSITION_UPDATE_INTERVAL) && self.stopping.is_none()
+                {
+                    self.position.set(self.current_position());
+                    self.update_fade_out();
+                }
+                Some(self.apply_fades(sample))
+            }
+            None => {
+                if !self.ended && self.stopping.is_none() {
+                    self.ended = true;
+                    let _ = self.events.send(TrackEvent::Ended(self.id));
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<S: Source + Send + 'static> Source for TrackSource<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        let ends_now = self.cancelled.load(Ordering::Relaxed)
+            && self.stopping.is_none()
+            && !self.fades_when_cancelled();
+        match &self.inner {
+            Some(inner) if !ends_now => inner.current_span_len(),
+            _ => Some(0),
+        }
+    }
+    fn channels(&self) -> ChannelCount {
+        self.inner
+            .as_ref()
+            .map_or(self.channels, |inner| inner.channels())
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.inner
+            .as_ref()
+            .map_or(self.sample_rate, |inner| inner.sample_rate())
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.as_ref().and_then(|inner| inner.total_duration())
+    }
+    fn try_seek(&mut self, position: Duration) -> Result<(), SeekError> {
+        let Some(inner) = &mut self.inner else {
+            return Err(SeekError::NotSupported {
+                underlying_source: std::any::type_name::<Self>(),
+            });
+        };
+        inner.try_seek(position)?;
+        self.offset = position;
+        self.samples = 0;
+        // The previous track's end belongs to where playback was
+        self.fade_in = None;
+        if self.started {
+            self.position.set(position);
+        }
+        self.update_fade_out();
+        Ok(())
+    }
+}
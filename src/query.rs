@@ -0,0 +1,601 @@
+//! Field filters for the library, e.g. `artist:"Boards of Canada" year:1995..2005 -live rating:>=4`
+use crate::library_db::SongStats;
+use crate::song::Song;
+use std::fmt;
+
+/// A parsed filter, a song matches if it matches every term
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Term {
+    negated: bool,
+    kind: TermKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TermKind {
+    /// Bare words match the title, artist, album or genre
+    Text(String),
+    Field(Field, Condition),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Year,
+    Track,
+    Duration,
+    Rating,
+    Plays,
+}
+impl Field {
+    const NAMES: [(&'static str, Field); 11] = [
+        ("title", Field::Title),
+        ("artist", Field::Artist),
+        ("album", Field::Album),
+        ("albumartist", Field::AlbumArtist),
+        ("genre", Field::Genre),
+        ("year", Field::Year),
+        ("track", Field::Track),
+        ("duration", Field::Duration),
+        ("rating", Field::Rating),
+        ("plays", Field::Plays),
+        ("playcount", Field::Plays),
+    ];
+    fn from_name(name: &str) -> Option<Field> {
+        Field::NAMES
+            .iter()
+            .find(|(field_name, _)| name.eq_ignore_ascii_case(field_name))
+            .map(|(_, field)| *field)
+    }
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Field::Year | Field::Track | Field::Duration | Field::Rating | Field::Plays
+        )
+    }
+    fn text<'a>(&self, song: &'a Song) -> Option<&'a str> {
+        match self {
+            Field::Title => Some(&song.title),
+            Field::Artist => song.artist.as_deref(),
+            Field::Album => song.album_title.as_deref(),
+            Field::AlbumArtist => song.album_artist.as_deref(),
+            Field::Genre => song.genre.as_deref(),
+            _ => None,
+        }
+    }
+    /// Numeric value of the field, durations are in seconds
+    fn number(&self, song: &Song, stats: &SongStats) -> Option<f64> {
+        match self {
+            Field::Year => song.year.map(f64::from),
+            Field::Track => song.index.map(f64::from),
+            Field::Duration => Some(song.duration.as_secs_f64()),
+            Field::Rating => stats.rating.map(f64::from),
+            Field::Plays => Some(f64::from(stats.play_count)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Contains(String),
+    Equal(f64),
+    Less(f64),
+    LessOrEqual(f64),
+    Greater(f64),
+    GreaterOrEqual(f64),
+    /// Inclusive range, either end may be open
+    Range(Option<f64>, Option<f64>),
+}
+impl Condition {
+    fn matches_number(&self, value: f64) -> bool {
+        match self {
+            Condition::Contains(_) => false,
+            Condition::Equal(n) => value == *n,
+            Condition::Less(n) => value < *n,
+            Condition::LessOrEqual(n) => value <= *n,
+            Condition::Greater(n) => value > *n,
+            Condition::GreaterOrEqual(n) => value >= *n,
+            Condition::Range(low, high) => {
+                low.is_none_or(|low| value >= low) && high.is_none_or(|high| value <= high)
+            }
+        }
+    }
+}
+
+/// Describes what is wrong with a query and where
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    /// Character position in the query, starting at 1
+    pub column: usize,
+    pub message: String,
+}
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.column)
+    }
+}
+impl std::error::Error for QueryError {}
+
+/// Returns whether the text uses filter syntax rather than being a plain search
+/// That's a known field like `artist:`, or leaving out a word with `-` next to other words,
+/// so titles like "Live: At Wembley" or "-ism" are searched for as they are
+pub fn is_filter(text: &str) -> bool {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let has_field = words.iter().any(|word| {
+        let word = word.strip_prefix('-').unwrap_or(word);
+        word.split_once(':')
+            .is_some_and(|(name, _)| Field::from_name(name).is_some())
+    });
+    let excludes = words.len() > 1
+        && words
+            .iter()
+            .any(|word| word.starts_with('-') && word.len() > 1);
+    has_field || excludes
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, QueryError> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut parser = Parser { chars, position: 0 };
+        let mut terms = vec![];
+        while let Some(term) = parser.term()? {
+            terms.push(term);
+        }
+        Ok(Query { terms })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+    pub fn matches(&self, song: &Song, stats: &SongStats) -> bool {
+        self.terms
+            .iter()
+            .all(|term| term.matches(song, stats) != term.negated)
+    }
+}
+
+impl Term {
+    fn matches(&self, song: &Song, stats: &SongStats) -> bool {
+        match &self.kind {
+            TermKind::Text(words) => [
+                Some(song.title.as_str()),
+                song.artist.as_deref(),
+                song.album_title.as_deref(),
+                song.genre.as_deref(),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|field| contains_ignore_case(field, words)),
+            TermKind::Field(field, Condition::Contains(value)) => field
+                .text(song)
+                .is_some_and(|text| contains_ignore_case(text, value)),
+            TermKind::Field(field, condition) => field
+                .number(song, stats)
+                .is_some_and(|value| condition.matches_number(value)),
+        }
+    }
+}
+
+fn contains_ignore_case(text: &str, pattern: &str) -> bool {
+    text.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+impl Parser {
+    fn error(&self, position: usize, message: impl Into<String>) -> QueryError {
+        QueryError {
+            column: position + 1,
+            message: message.into(),
+        }
+    }
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+    /// Parses the next term, or returns None at the end of the query
+    fn term(&mut self) -> Result<Option<Term>, QueryError> {
+        self.skip_whitespace();
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+        let start = self.position;
+        let negated = self.peek() == Some('-');
+        if negated {
+            self.position += 1;
+            if self.peek().is_none_or(char::is_whitespace) {
+                return Err(self.error(start, "Expected something to exclude after '-'"));
+            }
+        }
+
+        if self.peek() == Some('"') {
+            let text = self.quoted()?;
+            return Ok(Some(Term {
+                negated,
+                kind: TermKind::Text(text),
+            }));
+        }
+
+        // Read up to the end of the word or a ':' that starts a field value
+        let word_start = self.position;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ':' {
+                break;
+            }
+            if c == '"' {
+                return Err(self.error(
+                    self.position,
+                    "Quotes must start at the beginning of a value",
+                ));
+            }
+            self.position += 1;
+        }
+        let word: String = self.chars[word_start..self.position].iter().collect();
+        if self.peek() != Some(':') {
+            return Ok(Some(Term {
+                negated,
+                kind: TermKind::Text(word),
+            }));
+        }
+
+        if word.is_empty() {
+            return Err(self.error(word_start, "Missing field name before ':'"));
+        }
+        let Some(field) = Field::from_name(&word) else {
+            let known: Vec<&str> = Field::NAMES.iter().map(|(name, _)| *name).collect();
+            return Err(self.error(
+                word_start,
+                format!(
+                    "Unknown field '{}', expected one of: {}",
+                    word,
+                    known.join(", ")
+                ),
+            ));
+        };
+        self.position += 1; // Skip ':'
+
+        let value_start = self.position;
+        let value = if self.peek() == Some('"') {
+            self.quoted()?
+        } else {
+            while self.peek().is_some_and(|c| !c.is_whitespace()) {
+                self.position += 1;
+            }
+            self.chars[value_start..self.position].iter().collect()
+        };
+        if value.is_empty() {
+            return Err(self.error(value_start, format!("Expected a value after '{}:'", word)));
+        }
+
+        let condition = if field.is_numeric() {
+            self.numeric_condition(field, &value, value_start)?
+        } else {
+            if value.starts_with(['<', '>', '=']) {
+                return Err(self.error(
+                    value_start,
+                    format!(
+                        "'{}' is a text field and can't be compared with '{}'",
+                        word,
+                        &value[..1]
+                    ),
+                ));
+            }
+            Condition::Contains(value)
+        };
+
+        Ok(Some(Term {
+            negated,
+            kind: TermKind::Field(field, condition),
+        }))
+    }
+    /// Reads a "quoted value", starting at the opening quote
+    fn quoted(&mut self) -> Result<String, QueryError> {
+        let start = self.position;
+        self.position += 1; // Skip opening quote
+        let text_start = self.position;
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                let text: String = self.chars[text_start..self.position].iter().collect();
+                self.position += 1;
+                return Ok(text);
+            }
+            self.position += 1;
+        }
+        Err(self.error(start, "Missing closing quote"))
+    }
+    fn numeric_condition(
+        &self,
+        field: Field,
+        value: &str,
+        position: usize,
+    ) -> Result<Condition, QueryError> {
+        let parse = |text: &str| -> Result<f64, QueryError> {
+            let number = if field == Field::Duration {
+                parse_duration(text)
+            } else {
+                text.parse::<f64>().ok()
+            };
+            number.ok_or_else(|| {
+                let expected = if field == Field::Duration {
+                    "a duration like 90s, 5m, 1h or 3:30"
+                } else {
+                    "a number"
+                };
+                self.error(position, format!("'{}' is not {}", text, expected))
+            })
+        };
+
+        if let Some((low, high)) = value.split_once("..") {
+            if low.is_empty() && high.is_empty() {
+                return Err(self.error(position, "A range needs at least one end, e.g. 1990..2000"));
+            }
+            let low = (!low.is_empty()).then(|| parse(low)).transpose()?;
+            let high = (!high.is_empty()).then(|| parse(high)).transpose()?;
+            if let (Some(low), Some(high)) = (low, high)
+                && low > high
+            {
+                return Err(self.error(position, "The start of a range must not be after its end"));
+            }
+            return Ok(Condition::Range(low, high));
+        }
+        let condition = if let Some(rest) = value.strip_prefix(">=") {
+            Condition::GreaterOrEqual(parse(rest)?)
+        } else if let Some(rest) = value.strip_prefix("<=") {
+            Condition::LessOrEqual(parse(rest)?)
+        } else if let Some(rest) = value.strip_prefix('>') {
+            Condition::Greater(parse(rest)?)
+        } else if let Some(rest) = value.strip_prefix('<') {
+            Condition::Less(parse(rest)?)
+        } else if let Some(rest) = value.strip_prefix('=') {
+            Condition::Equal(parse(rest)?)
+        } else {
+            Condition::Equal(parse(value)?)
+        };
+        Ok(condition)
+    }
+}
+
+/// Parses durations such as 90, 90s, 5m, 1h30m or 3:30 into seconds
+fn parse_duration(text: &str) -> Option<f64> {
+    if text.is_empty() {
+        return None;
+    }
+    if text.contains(':') {
+        // [hours:]minutes:seconds
+        let mut seconds = 0.0;
+        for part in text.split(':') {
+            seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+        }
+        return Some(seconds);
+    }
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    for c in text.chars() {
+        let unit = match c {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+        seconds += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    // A trailing number without a unit is seconds
+    if !number.is_empty() {
+        seconds += number.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(text: &str) -> (Field, Condition) {
+        let query = Query::parse(text).unwrap();
+        match &query.terms[..] {
+            [
+                Term {
+                    negated: false,
+                    kind: TermKind::Field(field, condition),
+                },
+            ] => (*field, condition.clone()),
+            terms => panic!("expected a single field term, got {:?}", terms),
+        }
+    }
+
+    fn error(text: &str) -> QueryError {
+        Query::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn words_and_quotes() {
+        let query = Query::parse(r#"  boards "of canada" -live "#).unwrap();
+        let terms: Vec<(bool, TermKind)> = query
+            .terms
+            .into_iter()
+            .map(|term| (term.negated, term.kind))
+            .collect();
+        assert_eq!(
+            terms,
+            [
+                (false, TermKind::Text("boards".to_string())),
+                (false, TermKind::Text("of canada".to_string())),
+                (true, TermKind::Text("live".to_string())),
+            ]
+        );
+        assert!(Query::parse("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn text_fields() {
+        assert_eq!(
+            field(r#"artist:"Boards of Canada""#),
+            (
+                Field::Artist,
+                Condition::Contains("Boards of Canada".to_string())
+            )
+        );
+        assert_eq!(
+            field("AlbumArtist:Björk"),
+            (Field::AlbumArtist, Condition::Contains("Björk".to_string()))
+        );
+        let query = Query::parse("-genre:live").unwrap();
+        assert!(query.terms[0].negated);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(
+            field("rating:>=4"),
+            (Field::Rating, Condition::GreaterOrEqual(4.0))
+        );
+        assert_eq!(
+            field("rating:<=2"),
+            (Field::Rating, Condition::LessOrEqual(2.0))
+        );
+        assert_eq!(field("plays:>10"), (Field::Plays, Condition::Greater(10.0)));
+        assert_eq!(field("playcount:<3"), (Field::Plays, Condition::Less(3.0)));
+        assert_eq!(field("track:=7"), (Field::Track, Condition::Equal(7.0)));
+        assert_eq!(field("year:1995"), (Field::Year, Condition::Equal(1995.0)));
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            field("year:1995..2005"),
+            (Field::Year, Condition::Range(Some(1995.0), Some(2005.0)))
+        );
+        assert_eq!(
+            field("year:..2000"),
+            (Field::Year, Condition::Range(None, Some(2000.0)))
+        );
+        assert_eq!(
+            field("year:1990.."),
+            (Field::Year, Condition::Range(Some(1990.0), None))
+        );
+        // A range of one value is allowed
+        assert_eq!(
+            field("year:2000..2000"),
+            (Field::Year, Condition::Range(Some(2000.0), Some(2000.0)))
+        );
+        assert_eq!(
+            field("duration:3:00..4:30"),
+            (Field::Duration, Condition::Range(Some(180.0), Some(270.0)))
+        );
+    }
+
+    #[test]
+    fn ranges_include_both_ends() {
+        let range = Condition::Range(Some(1995.0), Some(2005.0));
+        assert!(!range.matches_number(1994.0));
+        assert!(range.matches_number(1995.0));
+        assert!(range.matches_number(2005.0));
+        assert!(!range.matches_number(2006.0));
+        let open_start = Condition::Range(None, Some(2000.0));
+        assert!(open_start.matches_number(f64::MIN));
+        assert!(open_start.matches_number(2000.0));
+        assert!(!open_start.matches_number(2000.5));
+        let open_end = Condition::Range(Some(1990.0), None);
+        assert!(!open_end.matches_number(1989.0));
+        assert!(open_end.matches_number(f64::MAX));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(90.0));
+        assert_eq!(parse_duration("90s"), Some(90.0));
+        assert_eq!(parse_duration("5m"), Some(300.0));
+        assert_eq!(parse_duration("1h30m"), Some(5400.0));
+        assert_eq!(parse_duration("2m30"), Some(150.0));
+        assert_eq!(parse_duration("3:30"), Some(210.0));
+        assert_eq!(parse_duration("1:02:03"), Some(3723.0));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("3:x"), None);
+        assert_eq!(parse_duration("five"), None);
+        assert_eq!(
+            field("duration:>3:30"),
+            (Field::Duration, Condition::Greater(210.0))
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let cases = [
+            ("-", 1, "Expected something to exclude after '-'"),
+            ("live - x", 6, "Expected something to exclude after '-'"),
+            (":rock", 1, "Missing field name before ':'"),
+            ("rock mood:happy", 6, "Unknown field 'mood'"),
+            ("artist:", 8, "Expected a value after 'artist:'"),
+            (r#"artist:"Boards"#, 8, "Missing closing quote"),
+            (
+                r#"boards"of"#,
+                7,
+                "Quotes must start at the beginning of a value",
+            ),
+            (
+                "title:>5",
+                7,
+                "'title' is a text field and can't be compared with '>'",
+            ),
+            ("year:abc", 6, "'abc' is not a number"),
+            ("year:>=", 6, "'' is not a number"),
+            ("duration:long", 10, "'long' is not a duration"),
+            ("year:..", 6, "A range needs at least one end"),
+            (
+                "year:2005..1995",
+                6,
+                "The start of a range must not be after its end",
+            ),
+            ("year:1990..x", 6, "'x' is not a number"),
+        ];
+        for (query, column, message) in cases {
+            let error = error(query);
+            assert_eq!(error.column, column, "column for {:?}", query);
+            assert!(
+                error.message.starts_with(message),
+                "message for {:?} was {:?}",
+                query,
+                error.message
+            );
+        }
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        assert_eq!(error("björk year:x").column, 12);
+    }
+
+    #[test]
+    fn filter_syntax() {
+        assert!(is_filter("artist:björk"));
+        assert!(is_filter("boards -live"));
+        assert!(!is_filter("boards of canada"));
+        assert!(!is_filter("rock - roll"));
+        assert!(is_filter("-genre:jazz"));
+        assert!(is_filter("YEAR:1995"));
+        // Colons and dashes that aren't filter syntax
+        assert!(!is_filter("Live: At Wembley"));
+        assert!(!is_filter("Re:Stacks"));
+        assert!(!is_filter("-ism"));
+        assert!(!is_filter("Jay-Z"));
+    }
+}
@@ -0,0 +1,170 @@
+//! ReplayGain tags and how they turn into the gain a song is played at
+use lofty::tag::{ItemKey, Tag};
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+
+/// Songs are brought to this loudness by their ReplayGain, in LUFS
+const REFERENCE_LUFS: f32 = -18.0;
+/// The ReplayGain reference level is 5 dB louder than the -23 LUFS that R128 tags are relative to
+const R128_TO_REPLAY_GAIN_DB: f32 = 5.0;
+
+/// A song's ReplayGain values, gains in dB and peaks as linear sample values where 1.0 is full scale
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+// Tag values are never NaN, so comparing and hashing the bits is consistent with PartialEq
+impl Eq for ReplayGain {}
+impl Hash for ReplayGain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for value in [
+            self.track_gain,
+            self.track_peak,
+            self.album_gain,
+            self.album_peak,
+        ] {
+            value.map(f32::to_bits).hash(state);
+        }
+    }
+}
+impl ReplayGain {
+    /// Fills in the values missing from these with the other ones
+    pub fn or(self, other: ReplayGain) -> Self {
+        Self {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+    /// Whether the song has no gain of its own and needs analysing
+    pub fn is_missing(&self) -> bool {
+        self.track_gain.is_none()
+    }
+    /// Reads the REPLAYGAIN_* tags, falling back to the R128_* gains Opus files use
+    pub fn from_tag(tag: &Tag) -> Self {
+        let value = |key: ItemKey| tag.get_string(&key).and_then(parse_value);
+        let r128 = |key: &str| {
+            tag.get_string(&ItemKey::Unknown(key.to_string()))
+                .and_then(parse_r128_gain)
+        };
+        Self {
+            track_gain: value(ItemKey::ReplayGainTrackGain).or_else(|| r128("R128_TRACK_GAIN")),
+            track_peak: value(ItemKey::ReplayGainTrackPeak),
+            album_gain: value(ItemKey::ReplayGainAlbumGain).or_else(|| r128("R128_ALBUM_GAIN")),
+            album_peak: value(ItemKey::ReplayGainAlbumPeak),
+        }
+    }
+}
+
+/// Loudness measured by the analysis job, kept for songs without ReplayGain tags
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AnalysedLoudness {
+    /// Integrated loudness in LUFS, None for silent songs
+    pub track_loudness: Option<f32>,
+    /// True peak, linear
+    pub track_peak: f32,
+    /// Loudness and peak of the song's whole album, None for songs without an album
+    pub album_loudness: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+impl AnalysedLoudness {
+    pub fn replay_gain(&self) -> ReplayGain {
+        let gain = |loudness: f32| REFERENCE_LUFS - loudness;
+        ReplayGain {
+            track_gain: self.track_loudness.map(gain),
+            track_peak: Some(self.track_peak),
+            album_gain: self.album_loudness.map(gain),
+            album_peak: self.album_peak,
+        }
+    }
+}
+
+/// Parses values such as "-6.54 dB" and "0.988312"
+fn parse_value(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let number = text
+        .strip_suffix("dB")
+        .or_else(|| text.strip_suffix("db"))
+        .unwrap_or(text);
+    number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|value: &f32| value.is_finite())
+}
+
+/// R128 gains are whole numbers of 1/256 dB relative to -23 LUFS
+fn parse_r128_gain(text: &str) -> Option<f32> {
+    let gain: i16 = text.trim().parse().ok()?;
+    Some(f32::from(gain) / 256.0 + R128_TO_REPLAY_GAIN_DB)
+}
+
+/// Which of a song's gains to play it at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain while an album plays in order, track gain otherwise
+    #[default]
+    Auto,
+}
+impl ReplayGainMode {
+    pub const ALL: [ReplayGainMode; 4] = [
+        ReplayGainMode::Off,
+        ReplayGainMode::Track,
+        ReplayGainMode::Album,
+        ReplayGainMode::Auto,
+    ];
+    pub const LABELS: [&'static str; 4] =
+        ["ReplayGain off", "Track gain", "Album gain", "Auto gain"];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// Added to every tagged song's gain, in dB
+    pub preamp: f32,
+    /// Lowers the gain where it would push the song's peak past full scale
+    pub prevent_clipping: bool,
+}
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Auto,
+            preamp: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+impl ReplayGainSettings {
+    /// Returns the factor to multiply the song's samples by
+    /// `in_album` tells auto mode that the song is playing as part of its album
+    pub fn factor(&self, tags: &ReplayGain, in_album: bool) -> f32 {
+        let track = (tags.track_gain, tags.track_peak);
+        let album = (tags.album_gain, tags.album_peak);
+        let use_album = match self.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => in_album,
+        };
+        // Use whichever gain the song has when the preferred one is missing
+        let (gain, peak) = match (use_album, album.0.is_some(), track.0.is_some()) {
+            (true, true, _) | (false, true, false) => album,
+            _ => track,
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+        let factor = 10f32.powf((gain + self.preamp) / 20.0);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use crate::app::Message;
+use crate::player::PlayerMessage;
+use crate::query::{Query, QueryError, is_filter};
+use crate::song::Song;
+use crate::song_library::{SongLibrary, most_common};
+use crate::sort_key::group_key;
+use cosmic::Element;
+use cosmic::iced::{Alignment, Length};
+use cosmic::widget::*;
+use std::collections::BTreeMap;
+
+const MAX_ARTISTS: usize = 5;
+const MAX_ALBUMS: usize = 10;
+const MAX_SONGS: usize = 50;
+/// Filter queries can match much of the library, only list this many
+const MAX_FILTERED_SONGS: usize = 200;
+/// Results scoring below this are not shown
+const MIN_SCORE: f32 = 0.5;
+
+#[derive(Debug, Clone)]
+pub enum SearchMessage {
+    Query(String),          // Updates the search text
+    Play(SearchResult),     // Replaces the queue with the result's songs
+    Enqueue(SearchResult),  // Adds the result's songs to the end of the queue
+    PlayNext(SearchResult), // Adds the result's songs right after the current song
+}
+
+/// Something that matched the search query
+#[derive(Debug, Clone)]
+pub enum SearchResult {
+    Artist(String),
+    /// By album key, see SongLibrary::album_key
+    Album(String),
+    Song(Song),
+    /// Every song matching a filter query
+    Filter(String),
+}
+
+/// Returns the songs a result refers to, in playing order
+pub fn songs_for(library: &SongLibrary, result: &SearchResult) -> Vec<Song> {
+    match result {
+        SearchResult::Artist(artist) => {
+            let key = group_key(artist);
+            let mut songs: Vec<Song> = library
+                .songs()
+                .iter()
+                .filter(|song| {
+                    [song.artist.as_deref(), song.album_artist.as_deref()]
+                        .into_iter()
+                        .flatten()
+                        .any(|song_artist| group_key(song_artist) == key)
+                })
+                .cloned()
+                .collect();
+            songs.sort_by(|a, b| {
+                a.sort_keys
+                    .album
+                    .cmp(&b.sort_keys.album)
+                    .then(a.index.cmp(&b.index))
+            });
+            songs
+        }
+        SearchResult::Album(key) => {
+            let mut songs = library.get_album(key);
+            songs.sort();
+            songs
+        }
+        SearchResult::Song(song) => vec![song.clone()],
+        SearchResult::Filter(filter) => match Query::parse(filter) {
+            Ok(query) => library
+                .songs()
+                .iter()
+                .filter(|song| query.matches(song, &library.stats(song)))
+                .cloned()
+                .collect(),
+            Err(_) => vec![],
+        },
+    }
+}
+
+/// The search field's text and what it found
+/// Searching happens when the text or the library changes, drawing only shows the results
+#[derive(Default)]
+pub struct Search {
+    pub query: String,
+    found: Found,
+}
+impl Search {
+    pub fn is_active(&self) -> bool {
+        !self.query.is_empty()
+    }
+    /// Searches the library again for the current query
+    pub fn refresh(&mut self, library: &SongLibrary) {
+        self.found = if self.query.is_empty() {
+            Found::Nothing
+        } else if is_filter(&self.query) {
+            Found::Filter(filter(library, &self.query))
+        } else {
+            Found::Search(search(library, &self.query))
+        };
+    }
+}
+
+#[derive(Default)]
+enum Found {
+    #[default]
+    Nothing,
+    Search(SearchResults),
+    /// Songs matching a filter query, as indices into the library's songs
+    Filter(Result<Vec<usize>, QueryError>),
+}
+
+/// Handles search messages, returning the player message to send if any
+pub fn update(
+    search: &mut Search,
+    library: &SongLibrary,
+    message: SearchMessage,
+) -> Option<PlayerMessage> {
+    match message {
+        SearchMessage::Query(query) => {
+            search.query = query;
+            search.refresh(library);
+            None
+        }
+        SearchMessage::Play(result) => {
+            Some(PlayerMessage::PlaySongs(songs_for(library, &result), 0))
+        }
+        SearchMessage::Enqueue(result) => {
+            Some(PlayerMessage::AddToQueue(songs_for(library, &result)))
+        }
+        SearchMessage::PlayNext(result) => {
+            Some(PlayerMessage::PlayNext(songs_for(library, &result)))
+        }
+    }
+}
+
+/// Library matches for a query, grouped by kind and ordered best match first
+/// Songs are indices into the library's songs
+pub struct SearchResults {
+    pub artists: Vec<String>,
+    pub albums: Vec<AlbumMatch>,
+    pub songs: Vec<usize>,
+}
+
+/// An album that matched, with the index of one of its songs for the cover and artist
+pub struct AlbumMatch {
+    pub key: String,
+    pub title: String,
+    pub song: usize,
+}
+
+pub fn search(library: &SongLibrary, query: &str) -> SearchResults {
+    let query_words = words(query);
+    // Spellings of each artist, keyed by group_key so "Björk" and "björk " are one artist
+    let mut artist_spellings: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    let mut albums: BTreeMap<String, (f32, &str, usize)> = BTreeMap::new();
+    let mut songs: Vec<(f32, usize)> = vec![];
+
+    for (index, song) in library.songs().iter().enumerate() {
+        for artist in [song.artist.as_deref(), song.album_artist.as_deref()]
+            .into_iter()
+            .flatten()
+        {
+            artist_spellings
+                .entry(group_key(artist))
+                .or_default()
+                .push(artist.trim());
+        }
+        if let Some(album) = song.album_title.as_deref()
+            && let Some(key) = library.album_key(song)
+            && !albums.contains_key(&key)
+        {
+            // Let "artist album" queries find the album as well
+            let artist = song.album_artist.as_deref().or(song.artist.as_deref());
+            let album_score = score(&query_words, album).max(
+                score(
+                    &query_words,
+                    &format!("{} {}", album, artist.unwrap_or_default()),
+                ) * 0.9,
+            );
+            albums.insert(key, (album_score, album.trim(), index));
+        }
+        // Titles count the most, but a song can also be found by its artist, album or genre
+        let full_text = format!(
+            "{} {} {} {}",
+            song.title,
+            song.artist.as_deref().unwrap_or_default(),
+            song.album_title.as_deref().unwrap_or_default(),
+            song.genre.as_deref().unwrap_or_default()
+        );
+        let song_score =
+            score(&query_words, &song.title).max(score(&query_words, &full_text) * 0.8);
+        if song_score >= MIN_SCORE {
+            songs.push((song_score, index));
+        }
+    }
+
+    let mut artists: Vec<(&str, f32)> = artist_spellings
+        .into_values()
+        .filter_map(|spellings| most_common(spellings.into_iter()))
+        .map(|artist| (artist, score(&query_words, artist)))
+        .filter(|(_, score)| *score >= MIN_SCORE)
+        .collect();
+    artists.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut albums: Vec<(String, (f32, &str, usize))> = albums
+        .into_iter()
+        .filter(|(_, (score, _, _))| *score >= MIN_SCORE)
+        .collect();
+    albums.sort_by(|a, b| b.1.0.total_cmp(&a.1.0));
+    songs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    SearchResults {
+        artists: artists
+            .into_iter()
+            .take(MAX_ARTISTS)
+            .map(|(artist, _)| artist.to_string())
+            .collect(),
+        albums: albums
+            .into_iter()
+            .take(MAX_ALBUMS)
+            .map(|(key, (_, title, song))| AlbumMatch {
+                key,
+                title: title.to_string(),
+                song,
+            })
+            .collect(),
+        songs: songs.into_iter().take(MAX_SONGS).map(|(_, s)| s).collect(),
+    }
+}
+
+/// Scores how well the text matches the query from 0 to 1
+/// Every query word has to match some word of the text, allowing for a few typos in longer words
+fn score(query_words: &[String], text: &str) -> f32 {
+    if query_words.is_empty() {
+        return 0.0;
+    }
+    let text_words = words(text);
+    let mut total = 0.0;
+    for query_word in query_words {
+        let best = text_words
+            .iter()
+            .map(|text_word| word_score(query_word, text_word))
+            .fold(0.0, f32::max);
+        if best == 0.0 {
+            return 0.0;
+        }
+        total += best;
+    }
+    let mut score = total / query_words.len() as f32;
+    // Prefer texts without lots of unmatched words
+    if text_words.len() == query_words.len() {
+        score += 0.05;
+    }
+    score.min(1.0)
+}
+
+fn word_score(query_word: &str, text_word: &str) -> f32 {
+    if query_word == text_word {
+        return 1.0;
+    }
+    if text_word.starts_with(query_word) {
+        return 0.9;
+    }
+    if text_word.contains(query_word) {
+        return 0.7;
+    }
+    let query_len = query_word.chars().count();
+    let allowed_typos = match query_len {
+        0..=3 => 0,
+        4..=6 => 1,
+        _ => 2,
+    };
+    if allowed_typos == 0 {
+        return 0.0;
+    }
+    // Compare against the whole word and against a prefix of the same length, so typos work while still typing
+    let prefix: String = text_word.chars().take(query_len).collect();
+    let distance = edit_distance(query_word, text_word).min(edit_distance(query_word, &prefix) + 1);
+    if distance <= allowed_typos {
+        0.8 - 0.15 * distance as f32
+    } else {
+        0.0
+    }
+}
+
+/// Optimal string alignment distance, counting a swap of two neighbouring characters as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Three rows of the distance matrix are enough to handle transpositions
+    let mut before_previous: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before_previous, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Splits text into lowercase words, ignoring punctuation
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Returns the indices of the songs matching a filter query, or what is wrong with the query
+fn filter(library: &SongLibrary, filter: &str) -> Result<Vec<usize>, QueryError> {
+    let query = Query::parse(filter)?;
+    Ok(library
+        .songs()
+        .iter()
+        .enumerate()
+        .filter(|(_, song)| query.matches(song, &library.stats(song)))
+        .map(|(index, _)| index)
+        .collect())
+}
+
+/// Draws the search results in place of the current page
+pub fn view<'a>(library: &'a SongLibrary, search: &'a Search) -> Element<'a, Message> {
+    let space = cosmic::theme::spacing().space_s;
+    let results = match &search.found {
+        Found::Nothing => return container(text("No results.")).padding(space).into(),
+        Found::Filter(songs) => return filter_view(library, &search.query, songs),
+        Found::Search(results) => results,
+    };
+
+    let mut content = column::with_capacity(6).spacing(space).padding(space);
+    if results.artists.is_empty() && results.albums.is_empty() && results.songs.is_empty() {
+        return container(text("No results.")).padding(space).into();
+    }
+    if !results.artists.is_empty() {
+        content = content.push(text::title4("Artists"));
+        for artist in &results.artists {
+            content = content.push(result_row(
+                text(artist.clone()).into(),
+                SearchResult::Artist(artist.clone()),
+            ));
+        }
+    }
+    if !results.albums.is_empty() {
+        content = content.push(text::title4("Albums"));
+        for album in &results.albums {
+            let song = &library.songs()[album.song];
+            let label = match song.album_artist.as_ref().or(song.artist.as_ref()) {
+                Some(artist) => format!("{} — {}", album.title, artist),
+                None => album.title.clone(),
+            };
+            let entry = row::with_capacity(2)
+                .push(image(song.picture.clone()).height(40))
+                .push(text(label))
+                .spacing(space)
+                .align_y(Alignment::Center);
+            content = content.push(result_row(
+                entry.into(),
+                SearchResult::Album(album.key.clone()),
+            ));
+        }
+    }
+    if !results.songs.is_empty() {
+        content = content.push(text::title4("Songs"));
+        for song in &results.songs {
+            let song = &library.songs()[*song];
+            content = content.push(result_row(song.display(), SearchResult::Song(song.clone())));
+        }
+    }
+
+    scrollable(content.width(Length::Fill)).into()
+}
+
+/// Lists the songs matching a filter query, or what is wrong with the query
+fn filter_view<'a>(
+    library: &'a SongLibrary,
+    filter: &str,
+    songs: &Result<Vec<usize>, QueryError>,
+) -> Element<'a, Message> {
+    let space = cosmic::theme::spacing().space_s;
+    let songs = match songs {
+        Ok(songs) => songs,
+        Err(error) => return container(text(error.to_string())).padding(space).into(),
+    };
+    if songs.is_empty() {
+        return container(text("No songs match this filter."))
+            .padding(space)
+            .into();
+    }
+
+    let summary = if songs.len() > MAX_FILTERED_SONGS {
+        format!(
+            "{} songs, showing the first {}",
+            songs.len(),
+            MAX_FILTERED_SONGS
+        )
+    } else {
+        format!("{} songs", songs.len())
+    };
+    let mut content = column::with_capacity(MAX_FILTERED_SONGS.min(songs.len()) + 1)
+        .push(result_row(
+            text::title4(summary).into(),
+            SearchResult::Filter(filter.to_string()),
+        ))
+        .spacing(space)
+        .padding(space);
+    for song in songs.iter().take(MAX_FILTERED_SONGS) {
+        let song = &library.songs()[*song];
+        content = content.push(result_row(song.display(), SearchResult::Song(song.clone())));
+    }
+
+    scrollable(content.width(Length::Fill)).into()
+}
+
+/// A result with its play and enqueue buttons
+fn result_row<'a>(label: Element<'a, Message>, result: SearchResult) -> Element<'a, Message> {
+    let space = cosmic::theme::spacing().space_s;
+    row::with_capacity(4)
+        .push(container(label).width(Length::Fill))
+        .push(
+            button::text("Play")
+                .on_press(Message::Search(SearchMessage::Play(result.clone())))
+                .class(cosmic::theme::Button::Suggested),
+        )
+        .push(
+            button::text("Play next")
+                .on_press(Message::Search(SearchMessage::PlayNext(result.clone()))),
+        )
+        .push(
+            button::text("Add to queue").on_press(Message::Search(SearchMessage::Enqueue(result))),
+        )
+        .spacing(space)
+        .align_y(Alignment::Center)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Vec<String> {
+        words(text)
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        // Swapped neighbours are a single typo
+        assert_eq!(edit_distance("beatels", "beatles"), 1);
+        assert_eq!(edit_distance("björk", "bjork"), 1);
+    }
+
+    #[test]
+    fn exact_prefix_and_inner_matches() {
+        assert_eq!(word_score("radiohead", "radiohead"), 1.0);
+        assert_eq!(word_score("radio", "radiohead"), 0.9);
+        assert_eq!(word_score("head", "radiohead"), 0.7);
+        assert!(word_score("radio", "radiohead") > word_score("radiohaed", "radiohead"));
+    }
+
+    #[test]
+    fn typos_allowed_by_word_length() {
+        // Short words have to be typed right
+        assert_eq!(word_score("abd", "abc"), 0.0);
+        // One typo from four letters, two from seven
+        assert!(word_score("mozrat", "mozart") > 0.0);
+        assert_eq!(word_score("mzrta", "mozart"), 0.0);
+        assert!(word_score("radiohaed", "radiohead") > word_score("rdiohaed", "radiohead"));
+        assert!(word_score("rdiohaed", "radiohead") > 0.0);
+        // A typo in a word that's still being typed
+        assert!(word_score("schubret", "schubertiade") > 0.0);
+    }
+
+    #[test]
+    fn every_query_word_has_to_match() {
+        assert!(score(&query("boards canada"), "Boards of Canada") >= MIN_SCORE);
+        assert_eq!(score(&query("boards jazz"), "Boards of Canada"), 0.0);
+        assert_eq!(score(&[], "Boards of Canada"), 0.0);
+        // Matching every word of the text ranks above matching a few of many
+        assert!(score(&query("roygb"), "Roygbiv") > score(&query("roygb"), "Roygbiv live"));
+    }
+
+    #[test]
+    fn results_best_match_first() {
+        let mut library = SongLibrary::default();
+        for (title, artist, album) in [
+            ("Everything in Its Right Place", "Radiohead", "Kid A"),
+            ("Creep", "Radiohead", "Pablo Honey"),
+            ("Creeping Death", "Metallica", "Ride the Lightning"),
+            ("Weird Fishes", "Radiohead", "In Rainbows"),
+        ] {
+            library.add_song(Song::for_test(title, Some(artist), Some(album)));
+        }
+        let titles = |results: &SearchResults| -> Vec<String> {
+            results
+                .songs
+                .iter()
+                .map(|index| library.songs()[*index].title.clone())
+                .collect()
+        };
+
+        let results = search(&library, "creep");
+        assert_eq!(titles(&results), ["Creep", "Creeping Death"]);
+
+        // Found by a typo, and by artist
+        let results = search(&library, "raidohead");
+        assert_eq!(results.artists, ["Radiohead"]);
+        assert_eq!(titles(&results).len(), 3);
+
+        let results = search(&library, "radiohead kid");
+        assert_eq!(
+            results.albums.first().map(|album| album.title.as_str()),
+            Some("Kid A")
+        );
+        assert_eq!(titles(&results), ["Everything in Its Right Place"]);
+    }
+}
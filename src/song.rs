@@ -5,20 +5,24 @@ extern crate cosmic;
 use crate::HEIGHT;
 use crate::app::Message;
 use crate::page::card_style;
+use crate::replay_gain::ReplayGain;
+use crate::sort_key::{SongSortKeys, SortTags};
 use cosmic::widget::*;
 use lofty::file::AudioFile;
 use lofty::file::TaggedFileExt;
 use lofty::tag::Accessor;
+use lofty::tag::ItemKey;
 use std::cmp::Ordering;
 use std::hash::Hash;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Song {
     pub title: String,
     pub artist: Option<String>,
+    pub album_artist: Option<String>,
     pub album_title: Option<String>,
     pub genre: Option<String>,
     pub year: Option<u32>,
@@ -26,6 +30,11 @@ pub struct Song {
     pub path: PathBuf,
     pub index: Option<u32>,
     pub duration: Duration,
+    pub date_added: SystemTime,
+    pub sort_tags: SortTags,
+    pub replay_gain: ReplayGain,
+    /// Filled in when the song is added to a library
+    pub sort_keys: SongSortKeys,
 }
 impl Ord for Song {
     fn cmp(&self, other: &Self) -> Ordering {
@@ -53,6 +62,7 @@ impl Song {
     pub fn new(
         title: String,
         artist: Option<String>,
+        album_artist: Option<String>,
         album_title: Option<String>,
         genre: Option<String>,
         year: Option<u32>,
@@ -60,10 +70,14 @@ impl Song {
         path: &Path,
         index: Option<u32>,
         duration: Duration,
+        date_added: SystemTime,
+        sort_tags: SortTags,
+        replay_gain: ReplayGain,
     ) -> Self {
         Self {
             title,
             artist,
+            album_artist,
             genre,
             album_title,
             year,
@@ -71,6 +85,10 @@ impl Song {
             path: path.to_path_buf(),
             index,
             duration,
+            date_added,
+            sort_tags,
+            replay_gain,
+            sort_keys: SongSortKeys::default(),
         }
     }
     pub fn display(&self) -> cosmic::Element<'static, Message> {
@@ -137,13 +155,29 @@ impl Song {
         }
         let index = file_tag.track();
         let artist = file_tag.artist().map(|artist| artist.to_string());
+        let album_artist = file_tag
+            .get_string(&ItemKey::AlbumArtist)
+            .map(|artist| artist.to_string());
         let genre = file_tag.genre().map(|genre| genre.to_string());
+        let tag_string = |key: ItemKey| file_tag.get_string(&key).map(|value| value.to_string());
+        let sort_tags = SortTags {
+            title: tag_string(ItemKey::TrackTitleSortOrder),
+            artist: tag_string(ItemKey::TrackArtistSortOrder),
+            album: tag_string(ItemKey::AlbumTitleSortOrder),
+            album_artist: tag_string(ItemKey::AlbumArtistSortOrder),
+        };
         let year = file_tag.year();
+        let replay_gain = ReplayGain::from_tag(file_tag);
+        // Use the file's creation time as the date it was added, falling back to modification time
+        let date_added = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
         let _ = writeln!(stderr_lock, "Done creating song: {:#?}", path);
 
         Ok(Self::new(
             title.to_string(),
             artist,
+            album_artist,
             album_title,
             genre,
             year,
@@ -151,6 +185,32 @@ impl Song {
             &path,
             index,
             duration,
+            date_added,
+            sort_tags,
+            replay_gain,
         ))
     }
 }
+
+#[cfg(test)]
+impl Song {
+    /// A song with only the tags tests look at, the path is made from the title
+    pub fn for_test(title: &str, artist: Option<&str>, album: Option<&str>) -> Song {
+        Song {
+            title: title.to_string(),
+            artist: artist.map(String::from),
+            album_artist: None,
+            album_title: album.map(String::from),
+            genre: None,
+            year: None,
+            picture: image::Handle::from_bytes(vec![]),
+            path: PathBuf::from(format!("{title}.flac")),
+            index: None,
+            duration: Duration::ZERO,
+            date_added: SystemTime::UNIX_EPOCH,
+            sort_tags: SortTags::default(),
+            replay_gain: ReplayGain::default(),
+            sort_keys: SongSortKeys::default(),
+        }
+    }
+}
@@ -1,7 +1,9 @@
+use crate::analysis::{AnalysisGroup, AnalysisResult};
 use crate::app::Message;
+use crate::library_db::{LibraryDb, SongStats};
 use crate::song::Song;
-use std::borrow::Cow;
-use std::collections::BTreeMap;
+use crate::sort_key::{SongSortKeys, group_key};
+use std::borrow::{Borrow, Cow};
 extern crate walkdir;
 use cosmic::{Action, Task};
 use std::collections::{HashMap, HashSet};
@@ -12,7 +14,11 @@ use walkdir::WalkDir;
 #[derive(Debug)]
 pub struct SongLibrary {
     songs: Vec<Song>,
-    pub show_album: Option<String>,
+    db: LibraryDb,
+    /// Leading articles ignored when sorting
+    articles: Vec<String>,
+    /// Goes up whenever songs or their stats change, so views built from the library know to rebuild
+    generation: u64,
 }
 impl SongLibrary {
     pub fn populate(path: PathBuf) -> Vec<cosmic::Task<Option<Song>>> {
@@ -54,50 +60,216 @@ impl SongLibrary {
 
         tasks
     }
-    pub fn add_song(&mut self, song: Song) {
+    pub fn add_song(&mut self, mut song: Song) {
         // eprintln!("Adding song {:#?}", song);
+        song.sort_keys = SongSortKeys::new(&song, &self.articles);
+        // Fill in gains missing from the tags with earlier analysis
+        if let Some(loudness) = self.db.stats(&song.path).and_then(|stats| stats.loudness) {
+            song.replay_gain = song.replay_gain.or(loudness.replay_gain());
+        }
         self.songs.push(song);
+        self.generation += 1;
     }
     pub fn default() -> Self {
         Self {
             songs: vec![].into_iter().collect(),
-            show_album: None,
+            db: LibraryDb::in_memory(),
+            articles: vec![],
+            generation: 0,
+        }
+    }
+    /// Creates an empty library backed by the saved song statistics
+    pub fn load(app_id: &str, articles: Vec<String>) -> Self {
+        Self {
+            songs: vec![],
+            db: LibraryDb::load(app_id),
+            articles,
+            generation: 0,
+        }
+    }
+    /// Changes the leading articles ignored when sorting, and makes every song's sort keys again
+    pub fn set_articles(&mut self, articles: Vec<String>) {
+        self.articles = articles;
+        for song in self.songs.iter_mut() {
+            song.sort_keys = SongSortKeys::new(song, &self.articles);
         }
+        self.generation += 1;
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+    /// Returns the play count, rating, etc. of the given song
+    pub fn stats(&self, song: &Song) -> SongStats {
+        self.db.stats(&song.path).cloned().unwrap_or_default()
+    }
+    pub fn record_play(&mut self, path: &Path) {
+        self.db.record_play(path);
+        self.generation += 1;
+    }
+    pub fn set_rating(&mut self, path: &Path, rating: Option<u8>) {
+        self.db.set_rating(path, rating);
+        self.generation += 1;
     }
-    pub fn get_album(&self, album: &str) -> Vec<Song> {
+    /// Stores the analysed loudness of songs, replacing the gains read from their tags where the result was written to them
+    /// Saves the database once for the whole batch, as it's written out in full every time
+    pub fn set_loudness(&mut self, results: Vec<AnalysisResult>) {
+        if results.is_empty() {
+            return;
+        }
+        for result in &results {
+            self.db.set_loudness(&result.path, result.loudness);
+        }
+        self.db.save();
+        let results: HashMap<&Path, &AnalysisResult> = results
+            .iter()
+            .map(|result| (result.path.as_path(), result))
+            .collect();
+        for song in self.songs.iter_mut() {
+            let Some(result) = results.get(song.path.as_path()) else {
+                continue;
+            };
+            song.replay_gain = if result.written {
+                result.loudness.replay_gain()
+            } else {
+                song.replay_gain.or(result.loudness.replay_gain())
+            };
+        }
+        self.generation += 1;
+    }
+    /// Returns the songs to analyse the loudness of, grouped by album
+    /// Whole albums are analysed when any of their songs has no gain, as the album's loudness needs all of them
+    pub fn songs_to_analyse(&self) -> Vec<AnalysisGroup> {
+        let mut albums: HashMap<String, Vec<&Song>> = HashMap::new();
+        let mut singles = vec![];
+        for song in self.songs.iter() {
+            match self.album_key(song) {
+                Some(key) => albums.entry(key).or_default().push(song),
+                None if song.replay_gain.is_missing() => singles.push(AnalysisGroup {
+                    paths: vec![song.path.clone()],
+                    is_album: false,
+                }),
+                None => {}
+            }
+        }
+        albums
+            .into_values()
+            .filter(|songs| songs.iter().any(|song| song.replay_gain.is_missing()))
+            .map(|songs| AnalysisGroup {
+                paths: songs.into_iter().map(|song| song.path.clone()).collect(),
+                is_album: true,
+            })
+            .chain(singles)
+            .collect()
+    }
+    /// Returns the key songs of the same album share, taking merged albums into account
+    pub fn album_key(&self, song: &Song) -> Option<String> {
+        let key = own_album_key(song)?;
+        Some(match self.db.merged_album(&key) {
+            Some(target) => target.to_string(),
+            None => key,
+        })
+    }
+    /// Merges the albums with the given keys into the first one
+    pub fn merge_albums(&mut self, keys: &[String]) {
+        self.db.merge_albums(keys);
+        self.generation += 1;
+    }
+    /// Splits albums that were merged into the album with the given key back out
+    pub fn unmerge_album(&mut self, key: &str) {
+        self.db.unmerge_album(key);
+        self.generation += 1;
+    }
+    pub fn has_merged_albums(&self, key: &str) -> bool {
+        self.db.has_merges(key)
+    }
+    /// Returns the songs of the album with the given key
+    pub fn get_album(&self, key: &str) -> Vec<Song> {
         // eprintln!("getting albums");
         let mut songs: Vec<Song> = vec![];
         for song in self.songs.iter() {
-            // eprintln!("{:#?}, {:#?}", song.album_title, album.to_string());
-            if song.album_title == Some(album.to_string()) {
+            if self.album_key(song).as_deref() == Some(key) {
                 songs.push(song.clone());
             }
         }
 
         songs
     }
-    pub fn get_albums(&self) -> BTreeMap<String, Vec<Song>> {
-        let mut songs: BTreeMap<String, Vec<Song>> = BTreeMap::new();
+    /// Returns every album, in no particular order
+    /// Titles that only differ in case, whitespace or Unicode normalisation are the same album
+    pub fn get_albums(&self) -> Vec<Album<'_>> {
+        let mut albums: HashMap<String, Vec<&Song>> = HashMap::new();
         // Loop over library songs
         for song in self.songs.iter() {
             // Get title, skip if none
-            let Some(album_title) = song.album_title.clone() else {
+            let Some(key) = self.album_key(song) else {
                 // eprintln!("No album title, not categorizing.");
                 continue;
             };
-            match songs.get_mut(&album_title) {
-                // If album exists, add current song to it
-                Some(entry) => {
-                    entry.push(song.clone());
-                    // eprintln!("Adding song to album");
-                }
-                // If entry does not exist, add song to new album
-                None => {
-                    songs.insert(album_title, vec![song.clone()]);
-                    // eprintln!("Adding song to library");
-                }
-            }
+            albums.entry(key).or_default().push(song);
         }
+        albums
+            .into_iter()
+            .map(|(key, songs)| Album {
+                key,
+                title: album_title(&songs),
+                songs,
+            })
+            .collect()
+    }
+}
+
+/// An album's songs, with the key that tells it apart and its title
+pub struct Album<'a> {
+    /// See SongLibrary::album_key
+    pub key: String,
+    pub title: String,
+    pub songs: Vec<&'a Song>,
+}
+
+/// The album key from the song's own tags, before merges
+/// Albums of the same title by different album artists, like two "Greatest Hits", are kept apart
+/// Songs without an album artist are grouped by title alone, so compilations tagged only with track artists stay together
+fn own_album_key(song: &Song) -> Option<String> {
+    let album = group_key(song.album_title.as_deref()?);
+    Some(
+        match song
+            .album_artist
+            .as_deref()
+            .filter(|artist| !artist.trim().is_empty())
+        {
+            Some(artist) => format!("{}\u{1f}{}", album, group_key(artist)),
+            None => album,
+        },
+    )
+}
+
+/// The most common spelling of an album's title
+pub fn album_title<S: Borrow<Song>>(songs: &[S]) -> String {
+    most_common(
         songs
+            .iter()
+            .filter_map(|song| song.borrow().album_title.as_deref().map(str::trim)),
+    )
+    .unwrap_or_default()
+    .to_string()
+}
+
+/// Returns the most common of the given spellings, the first one seen wins ties
+pub fn most_common<'a>(spellings: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut counts: Vec<(&str, usize)> = vec![];
+    for spelling in spellings {
+        match counts.iter_mut().find(|(s, _)| *s == spelling) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((spelling, 1)),
+        }
     }
+    // max_by_key returns the last maximum, so search from the back to prefer the first one seen
+    counts
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(spelling, _)| spelling)
 }
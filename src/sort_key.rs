@@ -0,0 +1,239 @@
+use crate::song::Song;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Leading articles ignored when sorting, unless the user configures their own
+pub const DEFAULT_ARTICLES: [&str; 5] = ["The", "A", "An", "Die", "Les"];
+
+/// The sort order tags of a song, e.g. ARTISTSORT "Beatles, The"
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SortTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+}
+
+/// Precomputed keys for sorting a song by its text fields
+/// Sort tags (TITLESORT, ARTISTSORT, ...) are used as is, other fields have their leading article removed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SongSortKeys {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub genre: String,
+}
+impl SongSortKeys {
+    pub fn new(song: &Song, articles: &[String]) -> Self {
+        let key = |sort_tag: &Option<String>, value: Option<&str>| match sort_tag {
+            Some(sort_tag) => fold(sort_tag),
+            None => sort_key(value.unwrap_or_default(), articles),
+        };
+        let tags = &song.sort_tags;
+        let artist = key(&tags.artist, song.artist.as_deref());
+        let album_artist = match (&tags.album_artist, &song.album_artist) {
+            (None, None) => artist.clone(),
+            (sort_tag, album_artist) => key(sort_tag, album_artist.as_deref()),
+        };
+        Self {
+            title: key(&tags.title, Some(&song.title)),
+            artist,
+            album: key(&tags.album, song.album_title.as_deref()),
+            album_artist,
+            genre: fold(song.genre.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+/// Returns a key that sorts text case-insensitively and ignoring accents and leading articles,
+/// so "the Beatles" sorts as "beatles" and "Ólafur Arnalds" next to "Oasis"
+pub fn sort_key(text: &str, articles: &[String]) -> String {
+    fold(strip_article(text.trim(), articles))
+}
+
+/// Lowercases text and removes accents by decomposing characters and dropping the combining marks
+/// Letters that don't decompose, like "ø" and "ß", are spelled out so they sort with their base letters
+pub fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text
+        .trim()
+        .chars()
+        .flat_map(char::to_lowercase)
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+    {
+        match fold_letter(c) {
+            Some(letters) => folded.push_str(letters),
+            None => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// The letters a lowercase letter without a decomposition sorts as, None for every other character
+fn fold_letter(c: char) -> Option<&'static str> {
+    let letters = match c {
+        'æ' => "ae",
+        'ð' | 'đ' => "d",
+        'ħ' => "h",
+        'ı' => "i",
+        'ł' => "l",
+        'ø' => "o",
+        'œ' => "oe",
+        'ß' => "ss",
+        'ς' => "σ",
+        'þ' => "th",
+        'ŧ' => "t",
+        _ => return None,
+    };
+    Some(letters)
+}
+
+/// Reads a comma separated list of articles, e.g. "The, A, An"
+pub fn parse_articles(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|article| !article.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Removes a leading article followed by a space, keeping the text if that would leave nothing
+fn strip_article<'a>(text: &'a str, articles: &[String]) -> &'a str {
+    for article in articles {
+        let Some(prefix) = text.get(..article.len()) else {
+            continue;
+        };
+        if !prefix.eq_ignore_ascii_case(article) {
+            continue;
+        }
+        let rest = &text[article.len()..];
+        if rest.starts_with(char::is_whitespace) && !rest.trim().is_empty() {
+            return rest.trim_start();
+        }
+    }
+    text
+}
+
+/// Returns the key used to decide whether two names are the same album or artist,
+/// ignoring differences in Unicode normalisation, case and surrounding whitespace
+/// Case is fully folded, so "STRASSE" and "Straße" are the same name
+pub fn group_key(text: &str) -> String {
+    let mut key = String::with_capacity(text.len());
+    for c in text.trim().nfc().flat_map(char::to_lowercase) {
+        match case_fold(c) {
+            Some(letters) => key.push_str(letters),
+            None => key.push(c),
+        }
+    }
+    key.nfc().collect()
+}
+
+/// Full case folding of the lowercase letters it differs from lowercasing for, None for every other character
+fn case_fold(c: char) -> Option<&'static str> {
+    let letters = match c {
+        'ß' => "ss",
+        'ς' => "σ",
+        'ſ' => "s",
+        'ﬀ' => "ff",
+        'ﬁ' => "fi",
+        'ﬂ' => "fl",
+        'ﬃ' => "ffi",
+        'ﬄ' => "ffl",
+        'ﬅ' | 'ﬆ' => "st",
+        _ => return None,
+    };
+    Some(letters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn articles() -> Vec<String> {
+        DEFAULT_ARTICLES.map(String::from).to_vec()
+    }
+
+    #[test]
+    fn case_and_accents() {
+        assert_eq!(fold("  Beatles "), "beatles");
+        assert_eq!(fold("Ólafur Arnalds"), "olafur arnalds");
+        assert_eq!(fold("Sigur Rós"), "sigur ros");
+        assert_eq!(fold("ＡＢＣ"), "abc");
+    }
+
+    #[test]
+    fn letters_without_decompositions() {
+        assert_eq!(fold("Øystein Sevåg"), "oystein sevag");
+        assert_eq!(fold("Łukasz"), "lukasz");
+        assert_eq!(fold("Æther"), "aether");
+        assert_eq!(fold("Œuvre"), "oeuvre");
+        assert_eq!(fold("Đorđe"), "dorde");
+        assert_eq!(fold("Straße"), "strasse");
+        assert_eq!(fold("STRAẞE"), "strasse");
+        assert_eq!(fold("Þursaflokkurinn"), "thursaflokkurinn");
+        assert_eq!(fold("Eðvarð"), "edvard");
+    }
+
+    #[test]
+    fn sorts_with_base_letters() {
+        let mut names = [
+            "Zappa",
+            "Øystein Sevåg",
+            "Łukasz",
+            "abba",
+            "Ólafur Arnalds",
+            "Oasis",
+            "The Beatles",
+            "Æther Realm",
+        ];
+        names.sort_by_key(|name| sort_key(name, &articles()));
+        assert_eq!(
+            names,
+            [
+                "abba",
+                "Æther Realm",
+                "The Beatles",
+                "Łukasz",
+                "Oasis",
+                "Ólafur Arnalds",
+                "Øystein Sevåg",
+                "Zappa",
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_articles() {
+        assert_eq!(sort_key("The Beatles", &articles()), "beatles");
+        assert_eq!(
+            sort_key("a tribe called quest", &articles()),
+            "tribe called quest"
+        );
+        assert_eq!(sort_key("Die Ärzte", &articles()), "arzte");
+        // Only whole words are articles, and a lone article is kept
+        assert_eq!(
+            sort_key("Theory of a Deadman", &articles()),
+            "theory of a deadman"
+        );
+        assert_eq!(sort_key("The", &articles()), "the");
+        assert_eq!(sort_key("The ", &articles()), "the");
+        assert_eq!(sort_key("The Beatles", &[]), "the beatles");
+    }
+
+    #[test]
+    fn article_lists() {
+        assert_eq!(parse_articles(" The, A ,,An "), ["The", "A", "An"]);
+        assert!(parse_articles(" , ").is_empty());
+    }
+
+    #[test]
+    fn group_keys() {
+        assert_eq!(group_key(" Björk"), group_key("bjo\u{308}rk "));
+        assert_ne!(group_key("Björk"), group_key("Bjork"));
+        assert_eq!(group_key("STRASSE"), group_key("Straße"));
+        assert_eq!(group_key("ΟΔΥΣΣΕΥΣ"), group_key("Οδυσσευς"));
+        assert_eq!(group_key("ﬁre"), group_key("Fire"));
+    }
+}
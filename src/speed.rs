@@ -0,0 +1,42 @@
+//! How fast songs play
+use serde::{Deserialize, Serialize};
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SpeedMode {
+    /// Plays the samples faster or slower, which raises or lowers the pitch like a record
+    Resample,
+    /// Stretches the audio in time, keeping voices sounding as they are
+    #[default]
+    TimeStretch,
+}
+impl SpeedMode {
+    pub const ALL: [SpeedMode; 2] = [SpeedMode::Resample, SpeedMode::TimeStretch];
+    pub const LABELS: [&'static str; 2] = ["Change pitch", "Keep pitch"];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpeedSettings {
+    /// 1 plays songs as they are, 2 twice as fast
+    pub speed: f32,
+    pub mode: SpeedMode,
+}
+impl Default for SpeedSettings {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            mode: SpeedMode::TimeStretch,
+        }
+    }
+}
+impl SpeedSettings {
+    pub fn speed(&self) -> f32 {
+        self.speed.clamp(MIN_SPEED, MAX_SPEED)
+    }
+    /// Songs play as they are, so there's nothing to do
+    pub fn is_normal(&self) -> bool {
+        self.speed() == 1.0
+    }
+}
@@ -0,0 +1,68 @@
+//! Album covers scaled down to the size the cards draw them at
+//! Embedded covers are often thousands of pixels wide, too much to upload and scale for every card
+use cosmic::widget::image::Handle;
+use image::imageops::FilterType;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Thumbnail sizes in pixels, so zooming only makes new thumbnails when it crosses one
+const SIZES: [u32; 5] = [96, 144, 208, 288, 400];
+
+/// The smallest thumbnail size that is at least as big as a cover drawn the given width
+pub fn size_for(width: f32) -> u32 {
+    SIZES
+        .into_iter()
+        .find(|size| *size as f32 >= width)
+        .unwrap_or(SIZES[SIZES.len() - 1])
+}
+
+/// Which cover a thumbnail is of, and at what size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThumbnailKey {
+    cover: u64,
+    size: u32,
+}
+impl ThumbnailKey {
+    pub fn new(cover: &Handle, size: u32) -> Self {
+        let mut hasher = DefaultHasher::new();
+        cover.hash(&mut hasher);
+        ThumbnailKey {
+            cover: hasher.finish(),
+            size,
+        }
+    }
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+/// Decodes a cover and crops and scales it to a square of the given size, the way the cards fit it
+/// Returns None for covers that aren't encoded images, or that can't be decoded
+pub fn make(cover: &Handle, size: u32) -> Option<Handle> {
+    let Handle::Bytes(_, bytes) = cover else {
+        return None;
+    };
+    let thumbnail = image::load_from_memory(bytes)
+        .ok()?
+        .resize_to_fill(size, size, FilterType::Triangle)
+        .into_rgba8();
+    Some(Handle::from_rgba(
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.into_raw(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_round_up() {
+        assert_eq!(size_for(10.0), 96);
+        assert_eq!(size_for(96.0), 96);
+        assert_eq!(size_for(97.0), 144);
+        assert_eq!(size_for(300.0), 400);
+        // Covers are never drawn bigger than the largest size, but don't panic if they are
+        assert_eq!(size_for(1000.0), 400);
+    }
+}
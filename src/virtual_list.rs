@@ -0,0 +1,194 @@
+//! Scrollable lists that only build the rows in view, so long lists stay fast to draw
+use crate::sort_key::fold;
+use cosmic::Element;
+use cosmic::iced::widget::scrollable::{self, AbsoluteOffset, Viewport};
+use cosmic::iced::{Alignment, Length};
+use cosmic::widget::*;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Rows built above and below the visible area so fast scrolling doesn't show blank space
+pub const OVERSCAN: usize = 10;
+
+/// Letters typed further apart than this start a new search
+const TYPE_TO_FIND_TIMEOUT: Duration = Duration::from_secs(1);
+/// The letters of the jump strip, '#' stands for everything that doesn't start with a letter
+pub const JUMP_LETTERS: &str = "#ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Where a virtual list is scrolled to, updated from its scrollable's viewport
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrollState {
+    pub offset: f32,
+    pub width: f32,
+    pub height: f32,
+    /// Lets the list be scrolled from update
+    id: Id,
+}
+impl Default for ScrollState {
+    fn default() -> Self {
+        // Assume a large window until the scrollable reports its size
+        Self {
+            offset: 0.0,
+            width: 1000.0,
+            height: 2000.0,
+            id: Id::unique(),
+        }
+    }
+}
+impl ScrollState {
+    pub fn update(&mut self, viewport: Viewport) {
+        self.offset = viewport.absolute_offset().y;
+        self.width = viewport.bounds().width;
+        self.height = viewport.bounds().height;
+    }
+    /// Scrolls the list so the given offset is at the top
+    pub fn scroll_to<T: 'static>(&self, offset: f32) -> cosmic::Task<T> {
+        scrollable::scroll_to(self.id.clone(), AbsoluteOffset { x: 0.0, y: offset })
+    }
+    /// Returns the rows to build when every row has the same height
+    pub fn visible(&self, len: usize, row_height: f32) -> Range<usize> {
+        let first = ((self.offset / row_height) as usize).saturating_sub(OVERSCAN);
+        let count = (self.height / row_height).ceil() as usize + OVERSCAN * 2;
+        let last = (first + count).min(len);
+        first.min(last)..last
+    }
+    /// Returns the rows to build when rows have different heights
+    pub fn visible_with_heights(&self, heights: &[f32]) -> Range<usize> {
+        // Offset of the top of every row, and the bottom of the last one
+        let mut tops = Vec::with_capacity(heights.len() + 1);
+        let mut top = 0.0;
+        for height in heights {
+            tops.push(top);
+            top += height;
+        }
+        tops.push(top);
+
+        let first = tops[..heights.len()]
+            .partition_point(|top| *top <= self.offset)
+            .saturating_sub(1 + OVERSCAN);
+        let last = (tops.partition_point(|top| *top < self.offset + self.height) + OVERSCAN)
+            .min(heights.len());
+        first.min(last)..last
+    }
+}
+
+/// How many cells of the given width fit next to each other in the list
+pub fn grid_columns(width: f32, cell_width: f32, spacing: f32) -> usize {
+    (((width + spacing) / (cell_width + spacing)) as usize).max(1)
+}
+
+/// A scrollable list of rows that all have the same height
+pub fn list<'a, Message: 'static>(
+    len: usize,
+    row_height: f32,
+    state: &ScrollState,
+    mut row: impl FnMut(usize) -> Element<'a, Message>,
+    on_scroll: impl Fn(Viewport) -> Message + 'a,
+) -> Element<'a, Message> {
+    let visible = state.visible(len, row_height);
+    let above = visible.start as f32 * row_height;
+    let below = (len - visible.end) as f32 * row_height;
+    build(
+        state,
+        visible,
+        above,
+        below,
+        |i| (row_height, row(i)),
+        on_scroll,
+    )
+}
+
+/// A scrollable list of rows with their own heights, e.g. grid rows with group headers in between
+pub fn list_with_heights<'a, Message: 'static>(
+    heights: &[f32],
+    state: &ScrollState,
+    mut row: impl FnMut(usize) -> Element<'a, Message>,
+    on_scroll: impl Fn(Viewport) -> Message + 'a,
+) -> Element<'a, Message> {
+    let visible = state.visible_with_heights(heights);
+    build(
+        state,
+        visible.clone(),
+        heights[..visible.start].iter().sum(),
+        heights[visible.end..].iter().sum(),
+        |i| (heights[i], row(i)),
+        on_scroll,
+    )
+}
+
+/// Builds the given rows, with empty space standing in for the rows above and below
+/// Each row is placed at the top of a slot of its height, so the slot also sets the gap to the next row
+fn build<'a, Message: 'static>(
+    state: &ScrollState,
+    visible: Range<usize>,
+    above: f32,
+    below: f32,
+    mut row: impl FnMut(usize) -> (f32, Element<'a, Message>),
+    on_scroll: impl Fn(Viewport) -> Message + 'a,
+) -> Element<'a, Message> {
+    let mut rows = column::with_capacity(visible.len() + 2)
+        .push(container(column::with_capacity::<Message>(0)).height(above));
+    for i in visible {
+        let (height, element) = row(i);
+        rows = rows.push(container(element).height(height).width(Length::Fill));
+    }
+    rows = rows.push(container(column::with_capacity::<Message>(0)).height(below));
+
+    scrollable(rows.width(Length::Fill))
+        .id(state.id.clone())
+        .on_scroll(on_scroll)
+        .height(Length::Fill)
+        .into()
+}
+
+/// The jump strip letter a sort key comes under
+pub fn jump_letter(key: &str) -> char {
+    match key.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => c.to_ascii_uppercase(),
+        _ => '#',
+    }
+}
+
+/// A vertical A-Z strip for jumping through a long list
+pub fn jump_strip<'a, Message: Clone + 'static>(
+    width: f32,
+    on_jump: impl Fn(char) -> Message,
+) -> Element<'a, Message> {
+    let mut strip = column::with_capacity(JUMP_LETTERS.len());
+    for letter in JUMP_LETTERS.chars() {
+        strip = strip.push(
+            button::custom(
+                text::caption(letter.to_string())
+                    .width(Length::Fill)
+                    .align_x(Alignment::Center),
+            )
+            .on_press(on_jump(letter))
+            .padding(0)
+            .width(Length::Fill)
+            .height(Length::Fill),
+        );
+    }
+    container(strip).width(width).height(Length::Fill).into()
+}
+
+/// Letters typed while a list is shown, matched against the start of its sort keys
+#[derive(Debug, Default)]
+pub struct TypeToFind {
+    text: String,
+    last_typed: Option<Instant>,
+}
+impl TypeToFind {
+    /// Adds a typed character, returning everything typed so far folded like a sort key
+    pub fn push(&mut self, c: char) -> String {
+        let now = Instant::now();
+        if self
+            .last_typed
+            .is_none_or(|last_typed| now.duration_since(last_typed) > TYPE_TO_FIND_TIMEOUT)
+        {
+            self.text.clear();
+        }
+        self.last_typed = Some(now);
+        self.text.push(c);
+        fold(&self.text)
+    }
+}